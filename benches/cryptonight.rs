@@ -2,7 +2,6 @@
 extern crate bencher;
 use bencher::Bencher;
 
-use rand;
 use rand::RngCore;
 
 fn bench_cryptonight(bench: &mut Bencher) {
@@ -15,5 +14,79 @@ fn bench_cryptonight(bench: &mut Bencher) {
     });
 }
 
-benchmark_group!(benches, bench_cryptonight);
+fn bench_hasher(bench: &mut Bencher) {
+    let mut rng = rand::thread_rng();
+    let mut input = [0u8; 128];
+    let mut hasher = ::cnminer::algorithm::Hasher::new();
+    bench.iter(|| {
+        rng.fill_bytes(&mut input);
+        let mut output = [0u8; 32];
+        hasher.hash(input.as_ref(), &mut output[..]);
+    });
+}
+
+fn bench_hasher_software_aes(bench: &mut Bencher) {
+    let mut rng = rand::thread_rng();
+    let mut input = [0u8; 128];
+    let mut hasher = ::cnminer::algorithm::Hasher::new().with_software_aes();
+    bench.iter(|| {
+        rng.fill_bytes(&mut input);
+        let mut output = [0u8; 32];
+        hasher.hash(input.as_ref(), &mut output[..]);
+    });
+}
+
+fn bench_hasher_regular_pages(bench: &mut Bencher) {
+    let mut rng = rand::thread_rng();
+    let mut input = [0u8; 128];
+    let mut hasher = ::cnminer::algorithm::Hasher::new().with_regular_pages();
+    bench.iter(|| {
+        rng.fill_bytes(&mut input);
+        let mut output = [0u8; 32];
+        hasher.hash(input.as_ref(), &mut output[..]);
+    });
+}
+
+/// Hashes per iteration are `ways`, compare time per hash across cases.
+fn bench_ways(bench: &mut Bencher, ways: usize) {
+    let mut rng = rand::thread_rng();
+    let mut inputs = vec![[0u8; 128]; ways];
+    let mut outputs = vec![[0u8; 32]; ways];
+    let mut hasher = ::cnminer::algorithm::Hasher::new().with_ways(ways);
+    bench.iter(|| {
+        for input in inputs.iter_mut() {
+            rng.fill_bytes(input);
+        }
+        let inputs: Vec<&[u8]> = inputs.iter().map(|input| input.as_ref()).collect();
+        hasher.hash_ways(&inputs, &mut outputs);
+    });
+}
+
+fn bench_hasher_2_ways(bench: &mut Bencher) {
+    bench_ways(bench, 2);
+}
+
+fn bench_hasher_3_ways(bench: &mut Bencher) {
+    bench_ways(bench, 3);
+}
+
+fn bench_hasher_4_ways(bench: &mut Bencher) {
+    bench_ways(bench, 4);
+}
+
+fn bench_hasher_5_ways(bench: &mut Bencher) {
+    bench_ways(bench, 5);
+}
+
+benchmark_group!(
+    benches,
+    bench_cryptonight,
+    bench_hasher,
+    bench_hasher_software_aes,
+    bench_hasher_regular_pages,
+    bench_hasher_2_ways,
+    bench_hasher_3_ways,
+    bench_hasher_4_ways,
+    bench_hasher_5_ways
+);
 benchmark_main!(benches);
@@ -0,0 +1,28 @@
+#[macro_use]
+extern crate bencher;
+use bencher::Bencher;
+
+use cnminer::algorithm::{keccakf, keccakf_ways};
+
+/// Four states permuted one after another.
+fn bench_keccakf(bench: &mut Bencher) {
+    let mut states = [[0x5a5a_5a5a_5a5a_5a5au64; 25]; 4];
+    bench.iter(|| {
+        for st in states.iter_mut() {
+            keccakf(st, 24);
+        }
+        states[0][0]
+    });
+}
+
+/// The same four states permuted together, with AVX2 where available.
+fn bench_keccakf_ways(bench: &mut Bencher) {
+    let mut states = [[0x5a5a_5a5a_5a5a_5a5au64; 25]; 4];
+    bench.iter(|| {
+        keccakf_ways(&mut states, 24);
+        states[0][0]
+    });
+}
+
+benchmark_group!(benches, bench_keccakf, bench_keccakf_ways);
+benchmark_main!(benches);
@@ -0,0 +1,69 @@
+//! Hardware AES rounds for x86_64.
+//!
+//! Everything here compiles to AES-NI instructions, so callers must check
+//! `available` first. The functions are always inlined, so they only turn
+//! into single instructions inside code compiled with the `aes` feature.
+
+//
+// Imports
+//
+
+use std::arch::x86_64::{
+    __m128i, _mm_aesenc_si128, _mm_loadu_si128, _mm_setzero_si128, _mm_storeu_si128,
+};
+
+//
+// Constants
+//
+
+const BLOCK_SIZE: usize = 16;
+
+//
+// Functions
+//
+
+/// Whether the CPU supports AES-NI.
+pub fn available() -> bool {
+    is_x86_feature_detected!("aes")
+}
+
+/// One AES round of `block`, keyed with `key`.
+///
+/// # Safety
+///
+/// The CPU must support AES-NI.
+#[inline(always)]
+pub unsafe fn round(block: &[u64; 2], key: &[u64; 2]) -> [u64; 2] {
+    let block = _mm_loadu_si128(block.as_ptr() as *const __m128i);
+    let key = _mm_loadu_si128(key.as_ptr() as *const __m128i);
+    let mut out = [0u64; 2];
+    _mm_storeu_si128(
+        out.as_mut_ptr() as *mut __m128i,
+        _mm_aesenc_si128(block, key),
+    );
+    out
+}
+
+/// Runs ten AES rounds on each of the eight blocks in `text`, keyed with
+/// the first ten round keys of `exp_data`.
+///
+/// # Safety
+///
+/// The CPU must support AES-NI.
+#[inline(always)]
+pub unsafe fn pseudo_rounds(text: &mut [u8; 8 * BLOCK_SIZE], exp_data: &[u8]) {
+    let mut blocks = [_mm_setzero_si128(); 8];
+    for (block, chunk) in blocks.iter_mut().zip(text.chunks_exact(BLOCK_SIZE)) {
+        *block = _mm_loadu_si128(chunk.as_ptr() as *const __m128i);
+    }
+    // Round by round, so the eight independent blocks fill the AES pipeline
+    for key in exp_data[..10 * BLOCK_SIZE].chunks_exact(BLOCK_SIZE) {
+        let key = _mm_loadu_si128(key.as_ptr() as *const __m128i);
+        for block in blocks.iter_mut() {
+            *block = _mm_aesenc_si128(*block, key);
+        }
+    }
+    for (block, chunk) in blocks.iter().zip(text.chunks_exact_mut(BLOCK_SIZE)) {
+        _mm_storeu_si128(chunk.as_mut_ptr() as *mut __m128i, *block);
+    }
+}
@@ -0,0 +1,97 @@
+//
+// Imports
+//
+
+use std::collections::hash_map::RandomState;
+use std::convert::TryFrom;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+//
+// Structures
+//
+
+/// Exponential reconnect backoff with random jitter.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    /// Delay before the first reconnect attempt.
+    pub initial: Duration,
+    /// Upper bound for the delay, before jitter.
+    pub max: Duration,
+    /// Factor the delay grows by after each failed attempt.
+    pub multiplier: f64,
+    /// Fraction of the delay randomly added or removed, between 0 and 1.
+    pub jitter: f64,
+}
+
+//
+// Implementations
+//
+
+impl Backoff {
+    /// Returns the delay before the given reconnect attempt, counting from 0.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = i32::try_from(attempt).unwrap_or(i32::MAX);
+        let base = self.initial.as_secs_f64() * self.multiplier.powi(exponent);
+        let base = base.min(self.max.as_secs_f64());
+        let jitter = base * self.jitter * (2.0 * random_unit() - 1.0);
+        Duration::from_secs_f64((base + jitter).max(0.0))
+    }
+}
+
+//
+// Trait implementations
+//
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.2,
+        }
+    }
+}
+
+//
+// Private Functions
+//
+
+/// Returns a random number in `[0, 1)`.
+fn random_unit() -> f64 {
+    // Every `RandomState` is freshly keyed, which is plenty for jitter
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exponential() {
+        let backoff = Backoff {
+            jitter: 0.0,
+            ..Backoff::default()
+        };
+        assert_eq!(backoff.delay(0), Duration::from_secs(1));
+        assert_eq!(backoff.delay(3), Duration::from_secs(8));
+        assert_eq!(backoff.delay(10), Duration::from_secs(60));
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn jitter() {
+        let backoff = Backoff::default();
+        for _ in 0..100 {
+            let delay = backoff.delay(2);
+            assert!(delay >= Duration::from_secs_f64(3.2));
+            assert!(delay <= Duration::from_secs_f64(4.8));
+        }
+    }
+}
@@ -7,20 +7,20 @@
 #[macro_use]
 extern crate clap;
 
-use cnminer::{Miner, MinerConf};
+use cnminer::{Miner, MinerConf, Pool, PoolUrl};
 
 //
 // Constants
 //
 
 // Donation configuration
-static DONATION_HOST: &'static str = "pool.minexmr.com";
-static DONATION_USER: &'static str = DONATION_ADDR_XMR;
-static DONATION_PASS: &'static str = "";
+static DONATION_HOST: &str = "pool.minexmr.com";
+static DONATION_USER: &str = DONATION_ADDR_XMR;
+static DONATION_PASS: &str = "";
 static DONATION_PORT: u16 = 4444;
 
 // Donation addresses
-static DONATION_ADDR_XMR: &'static str = "47wKntReuZyjA1GQTM27oPVvCrLFVX4AY5YiF8Ho4Q1UC97WDwcVnRrF3E7fd8nyVAhoKsRtzboru8zcJR46om1EQQSw8nX";
+static DONATION_ADDR_XMR: &str = "47wKntReuZyjA1GQTM27oPVvCrLFVX4AY5YiF8Ho4Q1UC97WDwcVnRrF3E7fd8nyVAhoKsRtzboru8zcJR46om1EQQSw8nX";
 
 // Target platform pointer width
 #[cfg(target_pointer_width = "32")]
@@ -40,40 +40,88 @@ fn main() {
         (about: "CPU miner for CryptoNote coins")
         (@arg user: --user -u +takes_value "Pool username")
         (@arg pass: --pass -p +takes_value "Pool password")
-        (@arg pool: --pool -x +takes_value "Stratum host:port")
+        (@arg pool: --pool -x +takes_value +multiple number_of_values(1)
+            "Pool URL as [stratum+tcp://|stratum+ssl://][user[:pass]@]host:port[?fingerprint=sha256], repeat to add failover pools. A fingerprint pins the certificate of that stratum+ssl pool in place of CA and hostname validation")
+        (@arg algo: --algo -a +takes_value "Algorithm to mine, such as cn/r or cn-lite/1 (default cn/0)")
+        (@arg ways: --ways +takes_value "Hashes each thread interleaves, 1 to 5 (default 1)")
+        (@arg threads: --threads -t +takes_value "Worker threads (default fits the scratchpads in L3)")
+        (@arg cpu_affinity: --("cpu-affinity") +takes_value "Mask of CPUs to run threads on, such as 0x0f")
         (@arg donate: --donate "Mine for the developer")
     )
     .get_matches();
 
-    // Parse pool address
-    let (pool_host, pool_port) = match matches.value_of("pool") {
-        Some(v) => {
-            let parts = v.split(":").collect::<Vec<_>>();
-            if parts.len() != 2 {
-                println!("ERROR: Please use the format 'host:port' for the pool!");
-                return;
+    // Parse pool addresses
+    let mut conf = MinerConf::default();
+    let mut pool_users = false;
+    match matches.values_of("pool") {
+        Some(values) => {
+            for v in values {
+                let url = match v.parse::<PoolUrl>() {
+                    Ok(url) => url,
+                    Err(err) => {
+                        println!("ERROR: Invalid pool '{}': {}", v, err);
+                        return;
+                    }
+                };
+                pool_users |= url.user.is_some();
+                conf = conf.with_pool(url);
             }
-            (parts[0], parts[1].parse().unwrap())
         }
-        None => (DONATION_HOST, DONATION_PORT),
+        None => conf.pools.push(Pool::new(DONATION_HOST, DONATION_PORT)),
     };
 
-    // Check donation mode
-    let donate = matches.is_present("donate") || !matches.is_present("user");
-
-    // Create mining configuration
-    let conf = MinerConf::default()
-        .with_pool(pool_host, pool_port)
-        .with_user(if donate {
-            DONATION_USER
-        } else {
-            matches.value_of("user").unwrap()
-        })
-        .with_pass(if donate {
-            DONATION_PASS
-        } else {
-            matches.value_of("pass").unwrap_or("")
-        });
+    // Check donation mode, pools may carry their own credentials
+    let donate = matches.is_present("donate") || !(matches.is_present("user") || pool_users);
+
+    // Select algorithm
+    if let Some(algo) = matches.value_of("algo") {
+        conf = conf.with_algorithm(algo);
+    }
+
+    // Select interleaved hashes per thread
+    if let Some(ways) = matches.value_of("ways") {
+        match ways.parse() {
+            Ok(ways) => conf = conf.with_ways(ways),
+            Err(_) => {
+                println!("ERROR: Invalid ways '{}'", ways);
+                return;
+            }
+        }
+    }
+
+    // Select threads and their placement
+    if let Some(threads) = matches.value_of("threads") {
+        match threads.parse() {
+            Ok(threads) => conf = conf.with_threads(threads),
+            Err(_) => {
+                println!("ERROR: Invalid threads '{}'", threads);
+                return;
+            }
+        }
+    }
+    if let Some(mask) = matches.value_of("cpu_affinity") {
+        let parsed = match mask.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => mask.parse(),
+        };
+        match parsed {
+            Ok(mask) => conf = conf.with_cpu_affinity(mask),
+            Err(_) => {
+                println!("ERROR: Invalid CPU affinity mask '{}'", mask);
+                return;
+            }
+        }
+    }
+
+    // Set credentials for pools without their own
+    if donate {
+        conf = conf.with_user(DONATION_USER).with_pass(DONATION_PASS);
+    } else {
+        if let Some(user) = matches.value_of("user") {
+            conf = conf.with_user(user);
+        }
+        conf = conf.with_pass(matches.value_of("pass").unwrap_or(""));
+    }
 
     // Print version info
     println!(
@@ -92,7 +140,13 @@ fn main() {
     }
 
     // Create miner
-    let mut miner = Miner::new(conf);
+    let mut miner = match Miner::new(conf) {
+        Ok(miner) => miner,
+        Err(err) => {
+            println!("ERROR: Invalid configuration: {}", err);
+            return;
+        }
+    };
 
     // Connect to pool
     miner.connect();
@@ -11,24 +11,64 @@
 // Imports
 //
 
-use super::keccak::{keccak, keccakf};
+#[cfg(target_arch = "x86_64")]
+use super::aesni;
+use super::keccak::{keccak, keccakf_ways};
+use super::memory::{Pages, Scratchpad};
 use super::oaes::AesContext;
+use super::random_math::Program;
 
 use blake::Blake;
 use groestl::{Digest, Groestl256};
-use jhffi as jh;
+use jh::{Digest as _, Jh256};
 use skeinffi as skein;
+use std::convert::TryInto;
 
 //
 // Constants
 //
 
-const MEMORY: usize = 1 << 21; // 2MB scratchpad
-const ITER: usize = 1 << 20;
 const AES_BLOCK_SIZE: usize = 16;
 const AES_KEY_SIZE: usize = 32;
 const INIT_SIZE_BLK: usize = 8;
 const INIT_SIZE_BYTE: usize = INIT_SIZE_BLK * AES_BLOCK_SIZE; // 256
+const HASH_SIZE: usize = 32;
+
+/// Most hashes a `Hasher` computes at once.
+pub const MAX_WAYS: usize = 5;
+
+//
+// Enumerations
+//
+
+/// CryptoNight variant.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Variant {
+    /// The original CryptoNight.
+    #[default]
+    V0,
+    /// Monero v7, tweaking the scratchpad writes with the nonce region of the input.
+    V1,
+    /// Monero v8, adding integer division, square root and a shuffle of
+    /// neighbouring scratchpad blocks.
+    V2,
+    /// CryptoNight-R (variant 4), replacing the integer math of variant 2
+    /// with a random program derived from the block height.
+    R,
+}
+
+/// Scratchpad size and iteration count of a CryptoNight family member.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Profile {
+    /// CryptoNight, with a 2 MB scratchpad.
+    #[default]
+    Standard,
+    /// CryptoNight-Lite, with a 1 MB scratchpad and half the iterations.
+    Lite,
+    /// CryptoNight-Heavy, with a 4 MB scratchpad, half the iterations, a
+    /// division in the main loop and extra mixing of the scratchpad.
+    Heavy,
+}
 
 //
 // Structures
@@ -36,30 +76,135 @@ const INIT_SIZE_BYTE: usize = INIT_SIZE_BLK * AES_BLOCK_SIZE; // 256
 
 /// CryptoNight Context.
 struct CNContext {
-    pub long_state: [u8; MEMORY],
+    /// The scratchpad, in 16-byte blocks.
+    pub long_state: Scratchpad,
     pub state: [u8; 400],
     pub text: [u8; INIT_SIZE_BYTE],
-    pub a: [u8; AES_BLOCK_SIZE],
-    pub b: [u8; AES_BLOCK_SIZE],
-    pub c: [u8; AES_BLOCK_SIZE],
 }
 
+/// Scratchpad size and iterations a hash runs with, those of its profile
+/// outside tests.
+#[derive(Clone, Copy)]
+struct Geometry {
+    memory: usize,
+    iterations: usize,
+    heavy: bool,
+}
+
+/// Reusable CryptoNight hasher.
+///
+/// Owns one scratchpad per way, which is allocated on the heap once and
+/// reused by every call to `hash` and `hash_ways`.
+pub struct Hasher {
+    contexts: Vec<CNContext>,
+    profile: Profile,
+    geometry: Geometry,
+    variant: Variant,
+    height: u64,
+    /// The CryptoNight-R program, generated on first use for each height.
+    program: Option<Program>,
+    /// Whether to use the AES-NI instructions instead of the T-tables.
+    hardware_aes: bool,
+    /// Whether to allocate scratchpads on huge pages where available.
+    huge_pages: bool,
+}
+
+/// Main loop state of one hash.
+#[derive(Clone, Copy, Default)]
+struct Lane {
+    a: [u64; 2],
+    b: [u64; 2],
+    /// The `b` of the previous iteration, for variant 2 and R.
+    b1: [u64; 2],
+    division_result: u64,
+    sqrt_result: u64,
+    /// Registers of the CryptoNight-R program.
+    r: [u32; 9],
+    address: u64,
+    tweak1_2: u64,
+}
+
+/// Portable AES, using four 1 KB lookup tables.
+struct SoftAes;
+
+/// AES-NI, only used after checking the CPU supports it.
+#[cfg(target_arch = "x86_64")]
+struct HardAes;
+
 //
 // Implementations
 //
 
+impl Profile {
+    /// Scratchpad size in bytes.
+    pub fn memory(self) -> usize {
+        match self {
+            Profile::Standard => 1 << 21,
+            Profile::Lite => 1 << 20,
+            Profile::Heavy => 1 << 22,
+        }
+    }
+
+    /// Main loop iterations, each reading and writing the scratchpad twice.
+    pub fn iterations(self) -> usize {
+        match self {
+            Profile::Standard => 1 << 19,
+            Profile::Lite | Profile::Heavy => 1 << 18,
+        }
+    }
+
+    /// Mask selecting a 16-byte aligned scratchpad offset from an address.
+    pub fn mask(self) -> u64 {
+        (self.memory() - AES_BLOCK_SIZE) as u64
+    }
+}
+
+impl Geometry {
+    fn of(profile: Profile) -> Geometry {
+        Geometry {
+            memory: profile.memory(),
+            iterations: profile.iterations(),
+            heavy: profile == Profile::Heavy,
+        }
+    }
+
+    /// Mask selecting a 16-byte aligned scratchpad offset from an address.
+    fn mask(self) -> u64 {
+        (self.memory - AES_BLOCK_SIZE) as u64
+    }
+}
+
 impl CNContext {
+    /// Constructs a context with a scratchpad of `memory` bytes, on huge
+    /// pages if `huge_pages` and the OS provides them.
+    fn new(memory: usize, huge_pages: bool) -> CNContext {
+        CNContext {
+            long_state: if huge_pages {
+                Scratchpad::new(memory)
+            } else {
+                Scratchpad::regular(memory)
+            },
+            state: [0u8; 400],
+            text: [0u8; INIT_SIZE_BYTE],
+        }
+    }
+
     #[inline(always)]
-    fn long_state(&self) -> &[u8] {
-        &self.long_state
+    fn memory(&self) -> usize {
+        self.long_state.len() * AES_BLOCK_SIZE
     }
+    #[cfg(not(feature = "safe"))]
     #[inline(always)]
-    fn long_state_mut(&mut self) -> &mut [u8] {
-        &mut self.long_state
+    fn long_state(&self) -> &[u8] {
+        unsafe {
+            ::std::slice::from_raw_parts(self.long_state.as_ptr() as *const u8, self.memory())
+        }
     }
+    #[cfg(not(feature = "safe"))]
     #[inline(always)]
-    fn state_k(&self) -> &[u8] {
-        &self.state.as_ref()[..64]
+    fn long_state_mut(&mut self) -> &mut [u8] {
+        let memory = self.memory();
+        unsafe { ::std::slice::from_raw_parts_mut(self.long_state.as_mut_ptr() as *mut u8, memory) }
     }
     #[inline(always)]
     fn state_b(&self) -> &[u8] {
@@ -70,31 +215,457 @@ impl CNContext {
         &mut self.state.as_mut()[..200]
     }
     #[inline(always)]
-    fn state_w_mut(&mut self) -> &mut [u64] {
-        unsafe { ::std::slice::from_raw_parts_mut(self.state.as_mut_ptr() as *mut u64, 25) }
-    }
-    #[inline(always)]
     fn state_init(&self) -> &[u8] {
-        unsafe { ::std::slice::from_raw_parts(self.state.as_ptr().offset(64), INIT_SIZE_BYTE) }
+        &self.state[64..64 + INIT_SIZE_BYTE]
     }
     #[inline(always)]
     fn state_init_mut(&mut self) -> &mut [u8] {
+        &mut self.state[64..64 + INIT_SIZE_BYTE]
+    }
+
+    /// Copies `text` to the scratchpad at byte `offset`.
+    #[cfg(not(feature = "safe"))]
+    #[inline(always)]
+    fn store_text(&mut self, offset: usize) {
+        let text = self.text;
+        self.long_state_mut()[offset..offset + INIT_SIZE_BYTE].copy_from_slice(&text);
+    }
+
+    /// Copies `text` to the scratchpad at byte `offset`.
+    #[cfg(feature = "safe")]
+    #[inline(always)]
+    fn store_text(&mut self, offset: usize) {
+        let blocks = &mut self.long_state[offset / AES_BLOCK_SIZE..];
+        for (block, bytes) in blocks
+            .iter_mut()
+            .zip(self.text.chunks_exact(AES_BLOCK_SIZE))
+        {
+            *block = [le_word(&bytes[..8]), le_word(&bytes[8..])];
+        }
+    }
+
+    /// XORs the scratchpad at byte `offset` into `text`.
+    #[cfg(not(feature = "safe"))]
+    #[inline(always)]
+    fn xor_text(&mut self, offset: usize) {
+        let mut block = [0u8; INIT_SIZE_BYTE];
+        block.copy_from_slice(&self.long_state()[offset..offset + INIT_SIZE_BYTE]);
+        for (text, byte) in self.text.iter_mut().zip(block.iter()) {
+            *text ^= byte;
+        }
+    }
+
+    /// XORs the scratchpad at byte `offset` into `text`.
+    #[cfg(feature = "safe")]
+    #[inline(always)]
+    fn xor_text(&mut self, offset: usize) {
+        let blocks = &self.long_state[offset / AES_BLOCK_SIZE..];
+        for (bytes, block) in self
+            .text
+            .chunks_exact_mut(AES_BLOCK_SIZE)
+            .zip(blocks.iter())
+        {
+            for (byte, word) in bytes.chunks_exact_mut(8).zip(block.iter()) {
+                for (byte, word_byte) in byte.iter_mut().zip(word.to_le_bytes().iter()) {
+                    *byte ^= word_byte;
+                }
+            }
+        }
+    }
+}
+
+impl Hasher {
+    /// Constructs a new standard variant 0 `Hasher`, allocating its scratchpad.
+    pub fn new() -> Hasher {
+        Hasher {
+            contexts: vec![CNContext::new(Profile::Standard.memory(), true)],
+            profile: Profile::Standard,
+            geometry: Geometry::of(Profile::Standard),
+            variant: Variant::V0,
+            height: 0,
+            program: None,
+            hardware_aes: hardware_aes_available(),
+            huge_pages: true,
+        }
+    }
+
+    pub fn with_profile(mut self, profile: Profile) -> Hasher {
+        self.set_profile(profile);
+        self
+    }
+
+    /// Switches to `profile`, reallocating the scratchpads if their size differs.
+    pub fn set_profile(&mut self, profile: Profile) {
+        self.set_geometry(Geometry::of(profile));
+        self.profile = profile;
+    }
+
+    /// Runs with `geometry`, reallocating the scratchpads if their size differs.
+    fn set_geometry(&mut self, geometry: Geometry) {
+        if geometry.memory != self.memory() {
+            for ctx in self.contexts.iter_mut() {
+                *ctx = CNContext::new(geometry.memory, self.huge_pages);
+            }
+        }
+        self.geometry = geometry;
+    }
+
+    /// The profile this hasher computes.
+    pub fn profile(&self) -> Profile {
+        self.profile
+    }
+
+    pub fn with_variant(mut self, variant: Variant) -> Hasher {
+        self.set_variant(variant);
+        self
+    }
+
+    pub fn set_variant(&mut self, variant: Variant) {
+        self.variant = variant;
+    }
+
+    /// The variant this hasher computes.
+    pub fn variant(&self) -> Variant {
+        self.variant
+    }
+
+    pub fn with_height(mut self, height: u64) -> Hasher {
+        self.set_height(height);
+        self
+    }
+
+    /// Sets the block height, which selects the CryptoNight-R program.
+    ///
+    /// Other variants ignore the height.
+    pub fn set_height(&mut self, height: u64) {
+        self.height = height;
+    }
+
+    /// The block height this hasher computes CryptoNight-R for.
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    pub fn with_ways(mut self, ways: usize) -> Hasher {
+        self.set_ways(ways);
+        self
+    }
+
+    /// Sets how many hashes `hash_ways` computes at once, allocating a
+    /// scratchpad for each.
+    ///
+    /// # Panics
+    ///
+    /// Panics unless `ways` is between 1 and `MAX_WAYS`.
+    pub fn set_ways(&mut self, ways: usize) {
+        assert!(
+            (1..=MAX_WAYS).contains(&ways),
+            "A hasher computes 1 to {} hashes at once",
+            MAX_WAYS
+        );
+        let (memory, huge_pages) = (self.geometry.memory, self.huge_pages);
+        self.contexts
+            .resize_with(ways, || CNContext::new(memory, huge_pages));
+    }
+
+    /// How many hashes `hash_ways` computes at once.
+    pub fn ways(&self) -> usize {
+        self.contexts.len()
+    }
+
+    /// Scratchpad size of each way in bytes.
+    pub fn memory(&self) -> usize {
+        self.contexts[0].memory()
+    }
+
+    /// The kind of pages backing the scratchpads, the least favourable if
+    /// they differ.
+    pub fn pages(&self) -> Pages {
+        self.contexts
+            .iter()
+            .map(|ctx| ctx.long_state.pages())
+            .min()
+            .unwrap()
+    }
+
+    /// Uses the portable AES implementation, even if the CPU supports AES-NI.
+    pub fn with_software_aes(mut self) -> Hasher {
+        self.hardware_aes = false;
+        self
+    }
+
+    /// Keeps the scratchpads on regular pages, even if huge pages are available.
+    pub fn with_regular_pages(mut self) -> Hasher {
+        self.huge_pages = false;
+        let memory = self.memory();
+        for ctx in self.contexts.iter_mut() {
+            *ctx = CNContext::new(memory, false);
+        }
+        self
+    }
+
+    /// Whether this hasher uses the AES-NI instructions.
+    pub fn hardware_aes(&self) -> bool {
+        self.hardware_aes
+    }
+
+    /// Hashes `input` into the first 32 bytes of `output`.
+    ///
+    /// # Panics
+    ///
+    /// Variant 1 panics on inputs shorter than 43 bytes.
+    pub fn hash(&mut self, input: &[u8], output: &mut [u8]) {
+        let mut hash = [[0u8; HASH_SIZE]];
+        self.run(&mut hash, &[input], 1);
+        output[..HASH_SIZE].copy_from_slice(&hash[0]);
+    }
+
+    /// Hashes one input per way at once, interleaving the hashes so each
+    /// hides the memory latency of the others.
+    ///
+    /// # Panics
+    ///
+    /// Panics unless there are exactly `ways` inputs and outputs. Variant 1
+    /// panics on inputs shorter than 43 bytes.
+    pub fn hash_ways(&mut self, inputs: &[&[u8]], outputs: &mut [[u8; HASH_SIZE]]) {
+        let ways = self.ways();
+        assert!(
+            inputs.len() == ways && outputs.len() == ways,
+            "Expected {} inputs and outputs",
+            ways
+        );
+        self.run(outputs, inputs, ways);
+    }
+
+    /// Hashes `inputs` with the first `ways` scratchpads.
+    fn run(&mut self, outputs: &mut [[u8; HASH_SIZE]], inputs: &[&[u8]], ways: usize) {
+        let program = match self.variant {
+            Variant::R => {
+                if self.program.as_ref().map(Program::height) != Some(self.height) {
+                    self.program = Some(Program::generate(self.height));
+                }
+                self.program.as_ref()
+            }
+            _ => None,
+        };
+        cn_hash_ctx(
+            outputs,
+            inputs,
+            &mut self.contexts[..ways],
+            self.geometry,
+            self.variant,
+            program,
+            self.hardware_aes,
+        );
+    }
+}
+
+impl Lane {
+    /// Derives the initial main loop state from the keccak state.
+    #[inline(always)]
+    fn new(state: &[u8], tweak1_2: u64) -> Lane {
+        let w = |i: usize| state_word(state, i);
+        // ((uint64_t *)(ctx->a))[i] = ((uint64_t *)ctx->state.k)[i] ^ ((uint64_t *)ctx->state.k)[i+4];
+        let a = [w(0) ^ w(4), w(1) ^ w(5)];
+        Lane {
+            a,
+            // ((uint64_t *)(ctx->b))[i] = ((uint64_t *)ctx->state.k)[i+2] ^ ((uint64_t *)ctx->state.k)[i+6];
+            b: [w(2) ^ w(6), w(3) ^ w(7)],
+            // VARIANT2_INIT64();
+            b1: [w(8) ^ w(10), w(9) ^ w(11)],
+            division_result: w(12),
+            sqrt_result: w(13),
+            // VARIANT4_RANDOM_MATH_INIT();
+            r: [
+                w(12) as u32,
+                (w(12) >> 32) as u32,
+                w(13) as u32,
+                (w(13) >> 32) as u32,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+            address: a[0],
+            tweak1_2,
+        }
+    }
+
+    /// Runs one main loop iteration, reading and writing the scratchpad twice.
+    #[inline(always)]
+    fn step<A: Aes>(
+        &mut self,
+        ls: &mut [[u64; 2]],
+        mask: u64,
+        variant: Variant,
+        program: Option<&Program>,
+        heavy: bool,
+    ) {
+        let v2 = variant == Variant::V2;
+        let shuffle = v2 || program.is_some();
+        let (a, b, b1) = (self.a, self.b, self.b1);
+        // Iteration 1
+        let j = block_index(self.address, mask);
+        let mut c = A::round(&ls[j], &a);
+        if shuffle {
+            let chunks = shuffle_add(ls, j, &a, &b, &b1);
+            if program.is_some() {
+                c = [c[0] ^ chunks[0], c[1] ^ chunks[1]];
+            }
+        }
+        ls[j] = [c[0] ^ b[0], c[1] ^ b[1]];
+        if variant == Variant::V1 {
+            variant1_1(&mut ls[j]);
+        }
+        // Iteration 2
+        let j = block_index(c[0], mask);
+        let mut d = ls[j];
+        let mut a1 = a;
+        if v2 {
+            integer_math(&mut d, &c, &mut self.division_result, &mut self.sqrt_result);
+        }
+        if let Some(program) = program {
+            random_math(program, &mut self.r, &mut a1, &mut d, &b, &b1);
+        }
+        let (mut hi, mut lo) = mul128(c[0], d[0]);
+        if v2 {
+            // VARIANT2_2();
+            ls[j ^ 1][0] ^= hi;
+            ls[j ^ 1][1] ^= lo;
+            hi ^= ls[j ^ 2][0];
+            lo ^= ls[j ^ 2][1];
+        }
+        if shuffle {
+            let chunks = shuffle_add(ls, j, &a, &b, &b1);
+            if program.is_some() {
+                c = [c[0] ^ chunks[0], c[1] ^ chunks[1]];
+            }
+        }
+        a1 = [a1[0].wrapping_add(hi), a1[1].wrapping_add(lo)];
+        // VARIANT1_2();
+        ls[j] = [a1[0], a1[1] ^ self.tweak1_2];
+        self.a = [a1[0] ^ d[0], a1[1] ^ d[1]];
+        self.b1 = b;
+        self.b = c;
+        self.address = if heavy {
+            heavy_division(&mut ls[block_index(self.a[0], mask)])
+        } else {
+            self.a[0]
+        };
+    }
+}
+
+//
+// Trait implementations
+//
+
+impl Default for Hasher {
+    fn default() -> Hasher {
+        Hasher::new()
+    }
+}
+
+#[cfg(not(feature = "safe"))]
+impl Aes for SoftAes {
+    #[inline(always)]
+    fn round(block: &[u64; 2], key: &[u64; 2]) -> [u64; 2] {
+        let mut out = [0u64; 2];
         unsafe {
-            ::std::slice::from_raw_parts_mut(self.state.as_mut_ptr().offset(64), INIT_SIZE_BYTE)
+            sub_and_shift_and_mix_add_round(
+                out.as_mut_ptr() as *mut u32,
+                block.as_ptr() as *const u8,
+                key.as_ptr() as *const u32,
+            );
+        }
+        out
+    }
+
+    #[inline(always)]
+    fn pseudo_rounds(text: &mut [u8; INIT_SIZE_BYTE], exp_data: &[u8]) {
+        // The key and text are byte arrays, so go through aligned words
+        for key in exp_data[..10 * AES_BLOCK_SIZE].chunks_exact(AES_BLOCK_SIZE) {
+            let key = key_words(key);
+            for block in text.chunks_exact_mut(AES_BLOCK_SIZE) {
+                let mut out = [0u32; 4];
+                unsafe {
+                    sub_and_shift_and_mix_add_round(out.as_mut_ptr(), block.as_ptr(), key.as_ptr());
+                }
+                for (bytes, word) in block.chunks_exact_mut(4).zip(out.iter()) {
+                    bytes.copy_from_slice(&word.to_le_bytes());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "safe")]
+impl Aes for SoftAes {
+    #[inline(always)]
+    fn round(block: &[u64; 2], key: &[u64; 2]) -> [u64; 2] {
+        let mut state = [0u8; AES_BLOCK_SIZE];
+        state[..8].copy_from_slice(&block[0].to_le_bytes());
+        state[8..].copy_from_slice(&block[1].to_le_bytes());
+        let key = [
+            key[0] as u32,
+            (key[0] >> 32) as u32,
+            key[1] as u32,
+            (key[1] >> 32) as u32,
+        ];
+        let out = soft_round(&state, &key);
+        [
+            u64::from(out[0]) | (u64::from(out[1]) << 32),
+            u64::from(out[2]) | (u64::from(out[3]) << 32),
+        ]
+    }
+
+    #[inline(always)]
+    fn pseudo_rounds(text: &mut [u8; INIT_SIZE_BYTE], exp_data: &[u8]) {
+        for key in exp_data[..10 * AES_BLOCK_SIZE].chunks_exact(AES_BLOCK_SIZE) {
+            let key = key_words(key);
+            for block in text.chunks_exact_mut(AES_BLOCK_SIZE) {
+                let out = soft_round(block, &key);
+                for (bytes, word) in block.chunks_exact_mut(4).zip(out.iter()) {
+                    bytes.copy_from_slice(&word.to_le_bytes());
+                }
+            }
         }
     }
 }
 
-impl Default for CNContext {
-    fn default() -> CNContext {
-        unsafe { ::std::mem::zeroed::<CNContext>() }
+#[cfg(target_arch = "x86_64")]
+impl Aes for HardAes {
+    #[inline(always)]
+    fn round(block: &[u64; 2], key: &[u64; 2]) -> [u64; 2] {
+        // HardAes is only used once AES-NI is known to be available
+        unsafe { aesni::round(block, key) }
+    }
+
+    #[inline(always)]
+    fn pseudo_rounds(text: &mut [u8; INIT_SIZE_BYTE], exp_data: &[u8]) {
+        unsafe { aesni::pseudo_rounds(text, exp_data) }
     }
 }
 
+//
+// Traits
+//
+
+/// An implementation of the AES rounds CryptoNight is built from.
+trait Aes {
+    /// One AES round of `block`, keyed with `key`.
+    fn round(block: &[u64; 2], key: &[u64; 2]) -> [u64; 2];
+
+    /// Runs the ten AES rounds of the explode and implode phases on each of
+    /// the eight blocks in `text`.
+    fn pseudo_rounds(text: &mut [u8; INIT_SIZE_BYTE], exp_data: &[u8]);
+}
+
 //
 // Macros
 //
 
+#[cfg(not(feature = "safe"))]
 macro_rules! sub_and_shift_and_mix_add_round {
     (
         $off:expr=>[s:$state:expr;k:$key:expr;o:$out:expr]
@@ -113,65 +684,183 @@ macro_rules! sub_and_shift_and_mix_add_round {
 // Public Functions
 //
 
+/// Hashes `input` with a one-off `Hasher`.
+///
+/// Allocates a fresh scratchpad, prefer a reused `Hasher` when hashing repeatedly.
 pub fn cryptonight(input: &[u8], output: &mut [u8]) {
-    let mut context = CNContext::default();
-    cn_hash_ctx(output, input, &mut context);
+    Hasher::new().hash(input, output);
 }
 
 //
 // Private Functions
 //
 
-#[inline]
-fn mul128(multiplier: u64, multiplicand: u64, product_hi: &mut u64) -> u64 {
-    let a = multiplier >> 32;
-    let b = multiplier & 0xFFFFFFFF;
-    let c = multiplicand >> 32;
-    let d = multiplicand & 0xFFFFFFFF;
-    let ad = a * d;
-    let bd = b * d;
-    let adbc = ad.wrapping_add(b * c);
-    let adbc_carry = if adbc < ad { 1 } else { 0 };
-    let product_lo = bd.wrapping_add(adbc << 32);
-    let product_lo_carry = if product_lo < bd { 1 } else { 0 };
-    *product_hi = (a * c)
-        .wrapping_add(adbc >> 32)
-        .wrapping_add(adbc_carry << 32)
-        .wrapping_add(product_lo_carry);
-    product_lo
+/// Multiplies two 64-bit words into the high and low halves of the product.
+#[inline(always)]
+fn mul128(multiplier: u64, multiplicand: u64) -> (u64, u64) {
+    let product = u128::from(multiplier) * u128::from(multiplicand);
+    ((product >> 64) as u64, product as u64)
 }
 
-#[inline]
-unsafe fn mul_sum_xor_dst(a: *const u8, c: *mut u8, dst: *mut u8) {
-    let a = a as *const u64;
-    let c = c as *mut u64;
-    let dst = dst as *mut u64;
-    let mut hi = 0u64;
-    let lo = mul128(*a, *dst, &mut hi).wrapping_add(*c.offset(1));
-    hi = hi.wrapping_add(*c);
-    *c = *dst ^ hi;
-    *c.offset(1) = *dst.offset(1) ^ lo;
-    *dst = hi;
-    *dst.offset(1) = lo;
+/// Reads a little-endian word of the keccak state.
+#[inline(always)]
+fn state_word(state: &[u8], index: usize) -> u64 {
+    let mut word = [0u8; 8];
+    word.copy_from_slice(&state[index * 8..index * 8 + 8]);
+    u64::from_le_bytes(word)
 }
 
-#[inline]
-unsafe fn xor_blocks(a: *mut u8, b: *const u8) {
-    let a = a as *mut u64;
-    let b = b as *mut u64;
-    *a ^= *b;
-    *a.offset(1) ^= *b.offset(1);
+/// Index of the scratchpad block at `address`.
+#[inline(always)]
+fn block_index(address: u64, mask: u64) -> usize {
+    ((address & mask) >> 4) as usize
 }
 
-#[inline]
-unsafe fn xor_blocks_dst(a: *const u8, b: *const u8, dst: *mut u8) {
-    let a64 = a as *const u64;
-    let b64 = b as *const u64;
-    let dst64 = dst as *mut u64;
-    *dst64 = *a64 ^ *b64;
-    *dst64.offset(1) = *a64.offset(1) ^ *b64.offset(1);
+/// VARIANT1_1: tweaks a block just written to the scratchpad.
+#[inline(always)]
+fn variant1_1(block: &mut [u64; 2]) {
+    let tmp = (block[1] >> 24) as u8;
+    let index = (((tmp >> 3) & 6) | (tmp & 1)) << 1;
+    block[1] ^= u64::from((0x75310u32 >> index) & 0x30) << 24;
+}
+
+/// VARIANT2_SHUFFLE_ADD: rotates the three blocks sharing a 64-byte line
+/// with block `j`, adding `b`, `a` and the previous `b` on the way.
+///
+/// Returns the XOR of the three blocks before the shuffle, which variant 4
+/// mixes into the AES output.
+#[inline(always)]
+fn shuffle_add(
+    ls: &mut [[u64; 2]],
+    j: usize,
+    a: &[u64; 2],
+    b: &[u64; 2],
+    b1: &[u64; 2],
+) -> [u64; 2] {
+    let chunk1 = ls[j ^ 1];
+    let chunk2 = ls[j ^ 2];
+    let chunk3 = ls[j ^ 3];
+    ls[j ^ 1] = [chunk3[0].wrapping_add(b1[0]), chunk3[1].wrapping_add(b1[1])];
+    ls[j ^ 2] = [chunk1[0].wrapping_add(b[0]), chunk1[1].wrapping_add(b[1])];
+    ls[j ^ 3] = [chunk2[0].wrapping_add(a[0]), chunk2[1].wrapping_add(a[1])];
+    [
+        chunk1[0] ^ chunk2[0] ^ chunk3[0],
+        chunk1[1] ^ chunk2[1] ^ chunk3[1],
+    ]
+}
+
+/// VARIANT4_RANDOM_MATH: mixes the previous program output into `d`, runs
+/// the program and mixes its output into `a`.
+#[inline(always)]
+fn random_math(
+    program: &Program,
+    r: &mut [u32; 9],
+    a: &mut [u64; 2],
+    d: &mut [u64; 2],
+    b: &[u64; 2],
+    b1: &[u64; 2],
+) {
+    d[0] ^= u64::from(r[0].wrapping_add(r[1])) | (u64::from(r[2].wrapping_add(r[3])) << 32);
+    r[4] = a[0] as u32;
+    r[5] = a[1] as u32;
+    r[6] = b[0] as u32;
+    r[7] = b1[0] as u32;
+    r[8] = b1[1] as u32;
+    program.execute(r);
+    a[0] ^= u64::from(r[2]) | (u64::from(r[3]) << 32);
+    a[1] ^= u64::from(r[0]) | (u64::from(r[1]) << 32);
+}
+
+/// CryptoNight-Heavy: divides the low word of `block` by part of its high
+/// word, storing the quotient in the block.
+///
+/// Returns the address of the next iteration.
+#[inline(always)]
+fn heavy_division(block: &mut [u64; 2]) -> u64 {
+    let n = block[0] as i64;
+    let d = block[1] as i32;
+    let q = n.wrapping_div(i64::from(d | 5));
+    block[0] = (n ^ q) as u64;
+    (i64::from(d) ^ q) as u64
+}
+
+/// CryptoNight-Heavy: XORs every block in `text` with the next, the last
+/// one wrapping around to the first.
+#[inline(always)]
+fn mix_and_propagate(text: &mut [u8; INIT_SIZE_BYTE]) {
+    let mut first = [0u8; AES_BLOCK_SIZE];
+    first.copy_from_slice(&text[..AES_BLOCK_SIZE]);
+    for i in 0..INIT_SIZE_BYTE - AES_BLOCK_SIZE {
+        text[i] ^= text[i + AES_BLOCK_SIZE];
+    }
+    for (byte, first) in text[INIT_SIZE_BYTE - AES_BLOCK_SIZE..]
+        .iter_mut()
+        .zip(first.iter())
+    {
+        *byte ^= first;
+    }
+}
+
+/// VARIANT2_INTEGER_MATH: mixes the previous division and square root
+/// into `d` and computes the next ones from `c`.
+#[inline(always)]
+fn integer_math(d: &mut [u64; 2], c: &[u64; 2], division_result: &mut u64, sqrt_result: &mut u64) {
+    d[0] ^= *division_result ^ (*sqrt_result << 32);
+    let dividend = c[1];
+    let divisor = u64::from((c[0] as u32).wrapping_add((*sqrt_result << 1) as u32) | 0x8000_0001);
+    *division_result =
+        ((dividend / divisor) & 0xffff_ffff).wrapping_add((dividend % divisor) << 32);
+    *sqrt_result = integer_sqrt(c[0].wrapping_add(*division_result));
 }
 
+/// Computes `floor(sqrt(2^64 + input) * 2 - 2^33)` exactly.
+///
+/// The floating point estimate is off by at most one, which the integer
+/// check corrects.
+#[inline(always)]
+fn integer_sqrt(input: u64) -> u64 {
+    let r = ((input as f64 + 18446744073709551616.0).sqrt() * 2.0 - 8589934592.0) as u64;
+    let s = r >> 1;
+    let b = r & 1;
+    let r2 = s.wrapping_mul(s + b).wrapping_add(r << 32);
+    let too_big = r2.wrapping_add(b) > input;
+    let too_small = r2.wrapping_add(1 << 32) < input.wrapping_sub(s);
+    r - too_big as u64 + too_small as u64
+}
+
+/// One T-table AES round of the 16 bytes of `s`, with bounds-checked lookups.
+#[cfg(feature = "safe")]
+#[inline(always)]
+fn soft_round(s: &[u8], key: &[u32; 4]) -> [u32; 4] {
+    let lut = |table: &[u32; 256], i: usize| table[usize::from(s[i])];
+    [
+        lut(&LUT1, 0) ^ lut(&LUT2, 5) ^ lut(&LUT3, 10) ^ lut(&LUT4, 15) ^ key[0],
+        lut(&LUT4, 3) ^ lut(&LUT1, 4) ^ lut(&LUT2, 9) ^ lut(&LUT3, 14) ^ key[1],
+        lut(&LUT3, 2) ^ lut(&LUT4, 7) ^ lut(&LUT1, 8) ^ lut(&LUT2, 13) ^ key[2],
+        lut(&LUT2, 1) ^ lut(&LUT3, 6) ^ lut(&LUT4, 11) ^ lut(&LUT1, 12) ^ key[3],
+    ]
+}
+
+/// Reads a 16-byte round key as little-endian words.
+#[inline(always)]
+fn key_words(key: &[u8]) -> [u32; 4] {
+    let mut words = [0u32; 4];
+    for (word, bytes) in words.iter_mut().zip(key.chunks_exact(4)) {
+        *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+    words
+}
+
+/// Reads a little-endian word from 8 bytes.
+#[cfg(feature = "safe")]
+#[inline(always)]
+fn le_word(bytes: &[u8]) -> u64 {
+    let mut word = [0u8; 8];
+    word.copy_from_slice(bytes);
+    u64::from_le_bytes(word)
+}
+
+#[cfg(not(feature = "safe"))]
 #[inline]
 unsafe fn sub_and_shift_and_mix_add_round(out: *mut u32, temp: *const u8, aes_enc_key: *const u32) {
     sub_and_shift_and_mix_add_round!(0 => [s:temp; k:aes_enc_key; o:out] i[0;5;10;15;k:0] lut[LUT1;LUT2;LUT3;LUT4]);
@@ -180,220 +869,192 @@ unsafe fn sub_and_shift_and_mix_add_round(out: *mut u32, temp: *const u8, aes_en
     sub_and_shift_and_mix_add_round!(3 => [s:temp; k:aes_enc_key; o:out] i[1;6;11;12;k:3] lut[LUT2;LUT3;LUT4;LUT1]);
 }
 
-#[inline]
-unsafe fn sub_and_shift_and_mix_add_round_in_place(temp: *mut u32, aes_enc_key: *const u32) {
-    let mut state_arr = [0u8; 16];
-    state_arr.copy_from_slice(::std::slice::from_raw_parts(temp as *const u8, 16));
-    let state = state_arr.as_ptr();
-    sub_and_shift_and_mix_add_round!(0 => [s:state; k:aes_enc_key; o:temp] i[0;5;10;15;k:0] lut[LUT1;LUT2;LUT3;LUT4]);
-    sub_and_shift_and_mix_add_round!(1 => [s:state; k:aes_enc_key; o:temp] i[3;4; 9;14;k:1] lut[LUT4;LUT1;LUT2;LUT3]);
-    sub_and_shift_and_mix_add_round!(2 => [s:state; k:aes_enc_key; o:temp] i[2;7; 8;13;k:2] lut[LUT3;LUT4;LUT1;LUT2]);
-    sub_and_shift_and_mix_add_round!(3 => [s:state; k:aes_enc_key; o:temp] i[1;6;11;12;k:3] lut[LUT2;LUT3;LUT4;LUT1]);
-}
-
-fn cn_hash_ctx(output: &mut [u8], input: &[u8], ctx: &mut CNContext) {
-    // ctx->aes_ctx = (oaes_ctx*) oaes_alloc();
-    // keccak((const uint8_t *)input, 76, ctx->state.hs.b, 200);
-    keccak(&input[..usize::min(input.len(), 76)], ctx.state_b_mut());
-    // memcpy(ctx->text, ctx->state.init, INIT_SIZE_BYTE);
-    for i in 0..INIT_SIZE_BYTE {
-        ctx.text[i] = ctx.state_init()[i];
+/// Whether to use AES-NI, which the CPU must support and safe mode rules out.
+fn hardware_aes_available() -> bool {
+    #[cfg(target_arch = "x86_64")]
+    {
+        // Safe mode sticks to the bounds-checked tables
+        !cfg!(feature = "safe") && aesni::available()
     }
-    // oaes_key_import_data(ctx->aes_ctx, ctx->state.hs.b, AES_KEY_SIZE);
-    let mut aes_ctx = AesContext::new(ctx.state_b(), AES_KEY_SIZE);
+    #[cfg(not(target_arch = "x86_64"))]
     {
-        let mut i = 0;
-        let text_ptr = ctx.text.as_mut_ptr();
-        let exp_ptr = aes_ctx.key.exp_data.as_ptr();
-        // for (; likely(i < MEMORY) ...
-        while i < MEMORY {
-            // for(j = 0; j < 10; j++)
-            for j in 0..10 {
-                unsafe {
-                    let exp_ptr = exp_ptr.offset(j << 4) as *const u32;
-                    sub_and_shift_and_mix_add_round_in_place(text_ptr as *mut u32, exp_ptr);
-                    sub_and_shift_and_mix_add_round_in_place(
-                        text_ptr.offset(0x10) as *mut u32,
-                        exp_ptr,
-                    );
-                    sub_and_shift_and_mix_add_round_in_place(
-                        text_ptr.offset(0x20) as *mut u32,
-                        exp_ptr,
-                    );
-                    sub_and_shift_and_mix_add_round_in_place(
-                        text_ptr.offset(0x30) as *mut u32,
-                        exp_ptr,
-                    );
-                    sub_and_shift_and_mix_add_round_in_place(
-                        text_ptr.offset(0x40) as *mut u32,
-                        exp_ptr,
-                    );
-                    sub_and_shift_and_mix_add_round_in_place(
-                        text_ptr.offset(0x50) as *mut u32,
-                        exp_ptr,
-                    );
-                    sub_and_shift_and_mix_add_round_in_place(
-                        text_ptr.offset(0x60) as *mut u32,
-                        exp_ptr,
-                    );
-                    sub_and_shift_and_mix_add_round_in_place(
-                        text_ptr.offset(0x70) as *mut u32,
-                        exp_ptr,
-                    );
-                }
-            }
-            // memcpy(&ctx->long_state[i], ctx->text, INIT_SIZE_BYTE);
-            for j in 0..INIT_SIZE_BYTE {
-                ctx.long_state_mut()[i + j] = ctx.text[j];
-            }
-            // ; i += INIT_SIZE_BYTE)
-            i += INIT_SIZE_BYTE;
-        }
+        false
     }
+}
+
+#[cfg_attr(not(target_arch = "x86_64"), allow(unused_variables))]
+fn cn_hash_ctx(
+    outputs: &mut [[u8; HASH_SIZE]],
+    inputs: &[&[u8]],
+    contexts: &mut [CNContext],
+    geometry: Geometry,
+    variant: Variant,
+    program: Option<&Program>,
+    hardware_aes: bool,
+) {
+    #[cfg(target_arch = "x86_64")]
     {
-        // ((uint64_t *)(ctx->a))
-        let a_ptr = ctx.a.as_mut_ptr() as *mut u64;
-        // ((uint64_t *)(ctx->b))
-        let b_ptr = ctx.b.as_mut_ptr() as *mut u64;
-        // ((uint64_t *)ctx->state.k)
-        let k_ptr = ctx.state_k().as_ptr() as *const u64;
-        // for (i = 0; i < 2; i++)
-        for i in 0..2 {
-            unsafe {
-                // ((uint64_t *)(ctx->a))[i] = ((uint64_t *)ctx->state.k)[i] ^ ((uint64_t *)ctx->state.k)[i+4];
-                *a_ptr.offset(i) = *k_ptr.offset(i) ^ *k_ptr.offset(i + 4);
-                // ((uint64_t *)(ctx->b))[i] = ((uint64_t *)ctx->state.k)[i+2] ^ ((uint64_t *)ctx->state.k)[i+6];
-                *b_ptr.offset(i) = *k_ptr.offset(i + 2) ^ *k_ptr.offset(i + 6);
-            }
+        if hardware_aes {
+            // The hasher only asks for AES-NI when the CPU supports it
+            unsafe { cn_hash_aesni(outputs, inputs, contexts, geometry, variant, program) };
+            return;
         }
     }
-    {
-        // i = 0;
-        let mut i = 0;
-        let a_ptr = ctx.a.as_mut_ptr();
-        let b_ptr = ctx.b.as_mut_ptr();
-        let c_ptr = ctx.c.as_mut_ptr();
-        let ls_ptr = ctx.long_state_mut().as_mut_ptr();
-        // for (; likely(i < ITER / 4) ...
-        while i < ITER / 4 {
-            unsafe {
-                // Step 1
-                // SubAndShiftAndMixAddRound((uint32_t *)ctx->c, (uint32_t *)&ctx->long_state[((uint64_t *)(ctx->a))[0] & 0x1FFFF0], (uint32_t *)ctx->a);
-                sub_and_shift_and_mix_add_round(
-                    c_ptr as *mut u32,
-                    ls_ptr.offset((*(a_ptr as *const u64) & 0x1ffff0) as isize),
-                    a_ptr as *const u32,
-                );
-                // xor_blocks_dst(ctx->c, ctx->b, &ctx->long_state[((uint64_t *)(ctx->a))[0] & 0x1FFFF0]);
-                xor_blocks_dst(
-                    c_ptr,
-                    b_ptr,
-                    ls_ptr.offset((*(a_ptr as *const u64) & 0x1ffff0) as isize),
-                );
-                // Step 2
-                // mul_sum_xor_dst(ctx->c, ctx->a, &ctx->long_state[((uint64_t *)(ctx->c))[0] & 0x1FFFF0]);
-                mul_sum_xor_dst(
-                    c_ptr,
-                    a_ptr,
-                    ls_ptr.offset((*(c_ptr as *const u64) & 0x1ffff0) as isize),
-                );
-                // Step 3
-                // SubAndShiftAndMixAddRound((uint32_t *)ctx->b, (uint32_t *)&ctx->long_state[((uint64_t *)(ctx->a))[0] & 0x1FFFF0], (uint32_t *)ctx->a);
-                sub_and_shift_and_mix_add_round(
-                    b_ptr as *mut u32,
-                    ls_ptr.offset((*(a_ptr as *const u64) & 0x1ffff0) as isize),
-                    a_ptr as *const u32,
-                );
-                // xor_blocks_dst(ctx->b, ctx->c, &ctx->long_state[((uint64_t *)(ctx->a))[0] & 0x1FFFF0]);
-                xor_blocks_dst(
-                    b_ptr,
-                    c_ptr,
-                    ls_ptr.offset((*(a_ptr as *const u64) & 0x1ffff0) as isize),
-                );
-                // Step 4
-                // mul_sum_xor_dst(ctx->b, ctx->a, &ctx->long_state[((uint64_t *)(ctx->b))[0] & 0x1FFFF0]);
-                mul_sum_xor_dst(
-                    b_ptr,
-                    a_ptr,
-                    ls_ptr.offset((*(b_ptr as *const u64) & 0x1ffff0) as isize),
-                );
-            }
-            // ; ++i)
-            i += 1;
+    match contexts.len() {
+        1 => cn_hash::<SoftAes, 1>(outputs, inputs, contexts, geometry, variant, program),
+        2 => cn_hash::<SoftAes, 2>(outputs, inputs, contexts, geometry, variant, program),
+        3 => cn_hash::<SoftAes, 3>(outputs, inputs, contexts, geometry, variant, program),
+        4 => cn_hash::<SoftAes, 4>(outputs, inputs, contexts, geometry, variant, program),
+        _ => cn_hash::<SoftAes, 5>(outputs, inputs, contexts, geometry, variant, program),
+    }
+}
+
+/// Compiles the whole hash with AES-NI enabled, so every round inlines to
+/// a single instruction.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "aes")]
+unsafe fn cn_hash_aesni(
+    outputs: &mut [[u8; HASH_SIZE]],
+    inputs: &[&[u8]],
+    contexts: &mut [CNContext],
+    geometry: Geometry,
+    variant: Variant,
+    program: Option<&Program>,
+) {
+    match contexts.len() {
+        1 => cn_hash::<HardAes, 1>(outputs, inputs, contexts, geometry, variant, program),
+        2 => cn_hash::<HardAes, 2>(outputs, inputs, contexts, geometry, variant, program),
+        3 => cn_hash::<HardAes, 3>(outputs, inputs, contexts, geometry, variant, program),
+        4 => cn_hash::<HardAes, 4>(outputs, inputs, contexts, geometry, variant, program),
+        _ => cn_hash::<HardAes, 5>(outputs, inputs, contexts, geometry, variant, program),
+    }
+}
+
+/// Hashes `N` inputs at once, interleaving their main loops so the memory
+/// latency of one hides behind the others.
+#[inline(always)]
+fn cn_hash<A: Aes, const N: usize>(
+    outputs: &mut [[u8; HASH_SIZE]],
+    inputs: &[&[u8]],
+    contexts: &mut [CNContext],
+    geometry: Geometry,
+    variant: Variant,
+    program: Option<&Program>,
+) {
+    let contexts: &mut [CNContext; N] = contexts.try_into().unwrap();
+    let mut lanes = [Lane::default(); N];
+    for ((lane, ctx), input) in lanes.iter_mut().zip(contexts.iter_mut()).zip(inputs) {
+        *lane = explode::<A>(ctx, input, geometry, variant);
+    }
+    let mask = geometry.mask();
+    let heavy = geometry.heavy;
+    // for (i = 0; i < ITER / 2; i++)
+    for _ in 0..geometry.iterations {
+        for (lane, ctx) in lanes.iter_mut().zip(contexts.iter_mut()) {
+            lane.step::<A>(&mut ctx.long_state, mask, variant, program, heavy);
+        }
+    }
+    for ctx in contexts.iter_mut() {
+        implode::<A>(ctx, geometry);
+    }
+    // keccakf((uint64_t *)ctx->state.hs.b, 24);
+    let mut states = [[0u64; 25]; N];
+    for (words, ctx) in states.iter_mut().zip(contexts.iter()) {
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = state_word(&ctx.state, i);
+        }
+    }
+    keccakf_ways(&mut states, 24);
+    for ((ctx, words), output) in contexts.iter_mut().zip(states.iter()).zip(outputs) {
+        for (bytes, word) in ctx.state_b_mut().chunks_exact_mut(8).zip(words.iter()) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        extra_hash(ctx.state_b(), output);
+    }
+}
+
+/// Absorbs `input` and fills the scratchpad, returning the main loop state.
+#[inline(always)]
+fn explode<A: Aes>(
+    ctx: &mut CNContext,
+    input: &[u8],
+    geometry: Geometry,
+    variant: Variant,
+) -> Lane {
+    // keccak((const uint8_t *)input, len, ctx->state.hs.b, 200);
+    keccak(input, ctx.state_b_mut());
+    // VARIANT1_INIT64();
+    let tweak1_2 = match variant {
+        Variant::V0 => 0,
+        Variant::V1 => {
+            assert!(
+                input.len() >= 43,
+                "CryptoNight variant 1 needs at least 43 bytes of input"
+            );
+            let mut nonce = [0u8; 8];
+            nonce.copy_from_slice(&input[35..43]);
+            state_word(&ctx.state, 24) ^ u64::from_le_bytes(nonce)
+        }
+        Variant::V2 | Variant::R => 0,
+    };
+    // memcpy(ctx->text, ctx->state.init, INIT_SIZE_BYTE);
+    for i in 0..INIT_SIZE_BYTE {
+        ctx.text[i] = ctx.state_init()[i];
+    }
+    // oaes_key_import_data(ctx->aes_ctx, ctx->state.hs.b, AES_KEY_SIZE);
+    let aes_ctx = AesContext::new(ctx.state_b(), AES_KEY_SIZE);
+    if geometry.heavy {
+        for _ in 0..16 {
+            A::pseudo_rounds(&mut ctx.text, &aes_ctx.key.exp_data);
+            mix_and_propagate(&mut ctx.text);
         }
     }
+    // for (i = 0; likely(i < MEMORY); i += INIT_SIZE_BYTE)
+    for i in (0..geometry.memory).step_by(INIT_SIZE_BYTE) {
+        A::pseudo_rounds(&mut ctx.text, &aes_ctx.key.exp_data);
+        // memcpy(&ctx->long_state[i], ctx->text, INIT_SIZE_BYTE);
+        ctx.store_text(i);
+    }
+    Lane::new(&ctx.state, tweak1_2)
+}
+
+/// Folds the scratchpad back into the state.
+#[inline(always)]
+fn implode<A: Aes>(ctx: &mut CNContext, geometry: Geometry) {
+    let heavy = geometry.heavy;
     // memcpy(ctx->text, ctx->state.init, INIT_SIZE_BYTE);
     for i in 0..INIT_SIZE_BYTE {
         ctx.text[i] = ctx.state_init()[i];
     }
-    // oaes_free((OAES_CTX **) &ctx->aes_ctx);
-    // ctx->aes_ctx = (oaes_ctx*) oaes_alloc();
     // oaes_key_import_data(ctx->aes_ctx, &ctx->state.hs.b[32], AES_KEY_SIZE);
-    aes_ctx = AesContext::new(&ctx.state_b()[32..], AES_KEY_SIZE);
-    {
-        // i = 0
-        let mut i = 0isize;
-        let text_ptr = ctx.text.as_mut_ptr();
-        let ls_ptr = ctx.long_state().as_ptr();
-        let exp_ptr = aes_ctx.key.exp_data.as_ptr();
-        // for(; likely(i < MEMORY) ...
-        while (i as usize) < MEMORY {
-            unsafe {
-                xor_blocks(text_ptr, ls_ptr.offset(i));
-                xor_blocks(text_ptr.offset(0x10), ls_ptr.offset(i + 0x10));
-                xor_blocks(text_ptr.offset(0x20), ls_ptr.offset(i + 0x20));
-                xor_blocks(text_ptr.offset(0x30), ls_ptr.offset(i + 0x30));
-                xor_blocks(text_ptr.offset(0x40), ls_ptr.offset(i + 0x40));
-                xor_blocks(text_ptr.offset(0x50), ls_ptr.offset(i + 0x50));
-                xor_blocks(text_ptr.offset(0x60), ls_ptr.offset(i + 0x60));
-                xor_blocks(text_ptr.offset(0x70), ls_ptr.offset(i + 0x70));
-                // for(j = 0; j < 10; j++)
-                for j in 0..10 {
-                    let exp_ptr = exp_ptr.offset(j << 4) as *const u32;
-                    sub_and_shift_and_mix_add_round_in_place(text_ptr as *mut u32, exp_ptr);
-                    sub_and_shift_and_mix_add_round_in_place(
-                        text_ptr.offset(0x10) as *mut u32,
-                        exp_ptr,
-                    );
-                    sub_and_shift_and_mix_add_round_in_place(
-                        text_ptr.offset(0x20) as *mut u32,
-                        exp_ptr,
-                    );
-                    sub_and_shift_and_mix_add_round_in_place(
-                        text_ptr.offset(0x30) as *mut u32,
-                        exp_ptr,
-                    );
-                    sub_and_shift_and_mix_add_round_in_place(
-                        text_ptr.offset(0x40) as *mut u32,
-                        exp_ptr,
-                    );
-                    sub_and_shift_and_mix_add_round_in_place(
-                        text_ptr.offset(0x50) as *mut u32,
-                        exp_ptr,
-                    );
-                    sub_and_shift_and_mix_add_round_in_place(
-                        text_ptr.offset(0x60) as *mut u32,
-                        exp_ptr,
-                    );
-                    sub_and_shift_and_mix_add_round_in_place(
-                        text_ptr.offset(0x70) as *mut u32,
-                        exp_ptr,
-                    );
-                }
+    let aes_ctx = AesContext::new(&ctx.state_b()[32..], AES_KEY_SIZE);
+    // Heavy makes a second pass over the scratchpad
+    for _ in 0..if heavy { 2 } else { 1 } {
+        // for (i = 0; likely(i < MEMORY); i += INIT_SIZE_BYTE)
+        for i in (0..geometry.memory).step_by(INIT_SIZE_BYTE) {
+            ctx.xor_text(i);
+            A::pseudo_rounds(&mut ctx.text, &aes_ctx.key.exp_data);
+            if heavy {
+                mix_and_propagate(&mut ctx.text);
             }
-            // ; i += INIT_SIZE_BYTE)
-            i += INIT_SIZE_BYTE as isize;
+        }
+    }
+    if heavy {
+        for _ in 0..16 {
+            A::pseudo_rounds(&mut ctx.text, &aes_ctx.key.exp_data);
+            mix_and_propagate(&mut ctx.text);
         }
     }
     // memcpy(ctx->state.init, ctx->text, INIT_SIZE_BYTE);
     for i in 0..INIT_SIZE_BYTE {
         ctx.state_init_mut()[i] = ctx.text[i];
     }
-    // keccakf((uint64_t *)ctx->state.hs.b, 24);
-    keccakf(ctx.state_w_mut(), 24);
-    // Extra hashes
+}
+
+/// Runs the final hash the permuted state selects.
+#[inline(always)]
+fn extra_hash(state: &[u8], output: &mut [u8]) {
     // extra_hashes[ctx->state.hs.b[0] & 3](&ctx->state, 200, output);
-    let state = ctx.state_b();
     match state[0] & 3 {
         0 => do_blake(state, output),
         1 => do_groestl(state, output),
@@ -403,7 +1064,6 @@ fn cn_hash_ctx(output: &mut [u8], input: &[u8], ctx: &mut CNContext) {
             unimplemented!()
         }
     };
-    // oaes_free((OAES_CTX **) &ctx->aes_ctx);
 }
 
 #[inline(always)]
@@ -425,7 +1085,7 @@ fn do_groestl(input: &[u8], output: &mut [u8]) {
 
 #[inline(always)]
 fn do_jh(input: &[u8], output: &mut [u8]) {
-    jh::hash(256, input, output).unwrap();
+    output[..32].copy_from_slice(&Jh256::digest(input));
 }
 
 #[inline(always)]
@@ -577,9 +1237,14 @@ static LUT4: [u32; 256] = [
     0x82C34141, 0x29B09999, 0x5A772D2D, 0x1E110F0F, 0x7BCBB0B0, 0xA8FC5454, 0x6DD6BBBB, 0x2C3A1616,
 ];
 
+//
+// Tests
+//
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::hex;
 
     macro_rules! check_hash {
         ($([$($a:expr),*] => [$($b:expr),*]),*) => {{
@@ -593,7 +1258,425 @@ mod tests {
         }};
     }
 
+    /// A hasher for every AES implementation the CPU supports.
+    fn hashers() -> Vec<Hasher> {
+        let mut hashers = vec![Hasher::new().with_software_aes()];
+        if hardware_aes_available() {
+            hashers.push(Hasher::new());
+        }
+        hashers
+    }
+
+    /// Shrinks `hasher` to a 4 KB scratchpad and 64 iterations, small enough
+    /// for Miri.
+    fn tiny(mut hasher: Hasher) -> Hasher {
+        hasher.set_geometry(Geometry {
+            memory: 1 << 12,
+            iterations: 1 << 6,
+            heavy: false,
+        });
+        hasher
+    }
+
+    #[test]
+    fn tiny_geometry() {
+        // Hashed with cn_slow_hash from Monero's slow-hash.c built with
+        // MEMORY (1 << 12) and ITER (1 << 7), picking inputs that end in
+        // Groestl and JH, as Miri can't run the Blake and Skein bindings
+        let vectors = [
+            (
+                49,
+                Variant::V0,
+                "6b89f911ce56167be7e7b49aa926ab09dd96cf483a64740fcba3495dbfbf82d5",
+            ),
+            (
+                14,
+                Variant::V1,
+                "467dbbeb42ec4ff999604115a794a03e8c3d852f8bde5ed2e36c4008bda9f3e9",
+            ),
+            (
+                21,
+                Variant::V1,
+                "26a9ac1225866b8df261a01cfb5ada26d84d522df1f25467c03e1f88441d83a8",
+            ),
+            (
+                42,
+                Variant::V0,
+                "e018a4b93275f43faabca90eb6d2ce1b58d797eb2bdc3648302c0461bf895741",
+            ),
+        ];
+        let input = |first: u8| (0..76).map(|i| first + i).collect::<Vec<u8>>();
+        for hasher in hashers() {
+            let mut hasher = tiny(hasher);
+            for &(first, variant, expected) in vectors.iter() {
+                hasher.set_variant(variant);
+                let mut output = [0u8; 32];
+                hasher.hash(&input(first), &mut output);
+                assert_eq!(hex::encode(&output), expected, "{:?}", variant);
+            }
+
+            // Interleaved, each way matching its single hash
+            hasher.set_variant(Variant::V0);
+            hasher.set_ways(2);
+            let inputs = [input(49), input(42)];
+            let mut outputs = [[0u8; HASH_SIZE]; 2];
+            hasher.hash_ways(&[&inputs[0], &inputs[1]], &mut outputs);
+            assert_eq!(hex::encode(&outputs[0]), vectors[0].2);
+            assert_eq!(hex::encode(&outputs[1]), vectors[3].2);
+            hasher.set_ways(1);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn long_input() {
+        // Counting bytes, past the 76-byte header and one and two 136-byte
+        // keccak blocks, hashed with cn_slow_hash from Monero's slow-hash.c
+        let vectors = [
+            (
+                77,
+                "1e774066f752fbd635433f5ab9e22904c7d7ed1c480a77d4df0e877f6892b1ea",
+            ),
+            (
+                136,
+                "d9bb0959b68ab1e0d747c3ae0a9977fca475af32a6d9d70787fbece42766f3e3",
+            ),
+            (
+                200,
+                "1adf947fecb24cb051de5e21d20becd0a00d16943b92312ed5f11a0bcc2bcee5",
+            ),
+            (
+                300,
+                "4a65a64282ede5f4b7f919b57c1b2d54b23138204152556183308311fd363130",
+            ),
+        ];
+        for mut hasher in hashers() {
+            for &(len, expected) in vectors.iter() {
+                let input = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+                let mut output = [0u8; 32];
+                hasher.hash(&input, &mut output);
+                assert_eq!(hex::encode(&output), expected, "input of {} bytes", len);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn variant1() {
+        // tests-slow-1.txt from the Monero repository
+        let vectors = [
+            (
+                "00000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+                "b5a7f63abb94d07d1a6445c36c07c7e8327fe61b1647e391b4c7edae5de57a3d",
+            ),
+            (
+                "8519e039172b0d70e5ca7b3383d6b3167315a422747b73f019cf9528f0fde341fd0f2a63030ba6450525cf6de31837669af6f1df8131faf50aaab8d3a7405589",
+                "5bb40c5880cef2f739bdb6aaaf16161eaae55530e7b10d7ea996b751a299e949",
+            ),
+            (
+                "37a636d7dafdf259b7287eddca2f58099e98619d2f99bdb8969d7b14498102cc065201c8be90bd777323f449848b215d2977c92c4c1c2da36ab46b2e389689ed97c18fec08cd3b03235c5e4c62a37ad88c7b67932495a71090e85dd4020a9300",
+                "613e638505ba1fd05f428d5c9f8e08f8165614342dac419adc6a47dce257eb3e",
+            ),
+            (
+                "38274c97c45a172cfc97679870422e3a1ab0784960c60514d816271415c306ee3a3ed1a77e31f6a885c3cb",
+                "ed082e49dbd5bbe34a3726a0d1dad981146062b39d36d62c71eb1ed8ab49459b",
+            ),
+        ];
+        for hasher in hashers() {
+            let mut hasher = hasher.with_variant(Variant::V1);
+            for &(input, expected) in vectors.iter() {
+                let mut output = [0u8; 32];
+                hasher.hash(&hex::decode(input).unwrap(), &mut output);
+                assert_eq!(hex::encode(&output), expected);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn variant2() {
+        // tests-slow-2.txt from the Monero repository
+        let vectors: [(&[u8], &str); 10] = [
+            (
+                b"This is a test This is a test This is a test",
+                "353fdc068fd47b03c04b9431e005e00b68c2168a3cc7335c8b9b308156591a4f",
+            ),
+            (
+                b"Lorem ipsum dolor sit amet, consectetur adipiscing",
+                "72f134fc50880c330fe65a2cb7896d59b2e708a0221c6a9da3f69b3a702d8682",
+            ),
+            (
+                b"elit, sed do eiusmod tempor incididunt ut labore",
+                "410919660ec540fc49d8695ff01f974226a2a28dbbac82949c12f541b9a62d2f",
+            ),
+            (
+                b"et dolore magna aliqua. Ut enim ad minim veniam,",
+                "4472fecfeb371e8b7942ce0378c0ba5e6d0c6361b669c587807365c787ae652d",
+            ),
+            (
+                b"quis nostrud exercitation ullamco laboris nisi",
+                "577568395203f1f1225f2982b637f7d5e61b47a0f546ba16d46020b471b74076",
+            ),
+            (
+                b"ut aliquip ex ea commodo consequat. Duis aute",
+                "f6fd7efe95a5c6c4bb46d9b429e3faf65b1ce439e116742d42b928e61de52385",
+            ),
+            (
+                b"irure dolor in reprehenderit in voluptate velit",
+                "422f8cfe8060cf6c3d9fd66f68e3c9977adb683aea2788029308bbe9bc50d728",
+            ),
+            (
+                b"esse cillum dolore eu fugiat nulla pariatur.",
+                "512e62c8c8c833cfbd9d361442cb00d63c0a3fd8964cfd2fedc17c7c25ec2d4b",
+            ),
+            (
+                b"Excepteur sint occaecat cupidatat non proident,",
+                "12a794c1aa13d561c9c6111cee631ca9d0a321718d67d3416add9de1693ba41e",
+            ),
+            (
+                b"sunt in culpa qui officia deserunt mollit anim id est laborum.",
+                "2659ff95fc74b6215c1dc741e85b7a9710101b30620212f80eb59c3c55993f9d",
+            ),
+        ];
+        for hasher in hashers() {
+            let mut hasher = hasher.with_variant(Variant::V2);
+            for &(input, expected) in vectors.iter() {
+                let mut output = [0u8; 32];
+                hasher.hash(input, &mut output);
+                assert_eq!(hex::encode(&output), expected);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn variant_r() {
+        // tests-slow-4.txt from the Monero repository, keyed by block height
+        let vectors: [(&[u8], u64, &str); 10] = [
+            (
+                b"This is a test This is a test This is a test",
+                1806260,
+                "f759588ad57e758467295443a9bd71490abff8e9dad1b95b6bf2f5d0d78387bc",
+            ),
+            (
+                b"Lorem ipsum dolor sit amet, consectetur adipiscing",
+                1806261,
+                "5bb833deca2bdd7252a9ccd7b4ce0b6a4854515794b56c207262f7a5b9bdb566",
+            ),
+            (
+                b"elit, sed do eiusmod tempor incididunt ut labore",
+                1806262,
+                "1ee6728da60fbd8d7d55b2b1ade487a3cf52a2c3ac6f520db12c27d8921f6cab",
+            ),
+            (
+                b"et dolore magna aliqua. Ut enim ad minim veniam,",
+                1806263,
+                "6969fe2ddfb758438d48049f302fc2108a4fcc93e37669170e6db4b0b9b4c4cb",
+            ),
+            (
+                b"quis nostrud exercitation ullamco laboris nisi",
+                1806264,
+                "7f3048b4e90d0cbe7a57c0394f37338a01fae3adfdc0e5126d863a895eb04e02",
+            ),
+            (
+                b"ut aliquip ex ea commodo consequat. Duis aute",
+                1806265,
+                "1d290443a4b542af04a82f6b2494a6ee7f20f2754c58e0849032483a56e8e2ef",
+            ),
+            (
+                b"irure dolor in reprehenderit in voluptate velit",
+                1806266,
+                "c43cc6567436a86afbd6aa9eaa7c276e9806830334b614b2bee23cc76634f6fd",
+            ),
+            (
+                b"Excepteur sint occaecat cupidatat non proident,",
+                1806268,
+                "dd9d6a6d8e47465cceac0877ef889b93e7eba979557e3935d7f86dce11b070f3",
+            ),
+            (
+                b"sunt in culpa qui officia deserunt mollit anim id est laborum.",
+                1806269,
+                "75c6f2ae49a20521de97285b431e717125847fb8935ed84a61e7f8d36a2c3d8e",
+            ),
+            (
+                b"esse cillum dolore eu fugiat nulla pariatur.",
+                1806267,
+                "87be2479c0c4e8edfdfaa5603e93f4265b3f8224c1c5946feb424819d18990a4",
+            ),
+        ];
+        for hasher in hashers() {
+            let mut hasher = hasher.with_variant(Variant::R);
+            for &(input, height, expected) in vectors.iter() {
+                let mut output = [0u8; 32];
+                hasher.set_height(height);
+                hasher.hash(input, &mut output);
+                assert_eq!(hex::encode(&output), expected, "height {}", height);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn profiles() {
+        // Test input and hashes of the xmrig miner
+        let input = hex::decode(
+            "0305a0dbd6bf05cf16e503f3a66f78007cbf34144332ecbfc22ed95c8700383b309ace1923a0964b00000008ba939a62724c0d7581fce5761e9d8a0e6a1c3f924fdd8493d1115649c05eb601",
+        )
+        .unwrap();
+        let vectors = [
+            (
+                Profile::Standard,
+                Variant::V0,
+                "1a3ffbee909b420d91f7be6e5fb56db71b3110d886011e877ee5786afd080100",
+            ),
+            (
+                Profile::Lite,
+                Variant::V0,
+                "3695b4b53bb00358b0ad38dc160feb9e004eece09b83a72ef6ba9864d3510c88",
+            ),
+            (
+                Profile::Lite,
+                Variant::V1,
+                "6d8cdc444e9bbbfd68fc43fcd4855b228c8a1bd91d9d00285bec02b7ca2d6741",
+            ),
+            (
+                Profile::Heavy,
+                Variant::V0,
+                "9983f21bdf2010a8d707bb2f14d78664bbe1187f55014b39e5f3d69328e48fc2",
+            ),
+        ];
+        for hasher in hashers() {
+            let mut hasher = hasher;
+            for &(profile, variant, expected) in vectors.iter() {
+                hasher.set_profile(profile);
+                hasher.set_variant(variant);
+                let mut output = [0u8; 32];
+                hasher.hash(&input, &mut output);
+                assert_eq!(
+                    hex::encode(&output),
+                    expected,
+                    "{:?} {:?}, hardware AES {}",
+                    profile,
+                    variant,
+                    hasher.hardware_aes()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn square_root() {
+        // Bit-by-bit reference from the Monero sources
+        fn reference(mut n: u64) -> u64 {
+            let mut r = 1u64 << 63;
+            let mut bit = 1u64 << 60;
+            while bit != 0 {
+                let b = n < r.wrapping_add(bit);
+                let n_next = n.wrapping_sub(r.wrapping_add(bit));
+                let r_next = r.wrapping_add(bit * 2);
+                n = if b { n } else { n_next };
+                r = if b { r } else { r_next };
+                r >>= 1;
+                bit >>= 2;
+            }
+            // The loop starts from the implicit 2^64, remove its 2^33
+            (r * 2 + (n > r) as u64).wrapping_sub(1 << 33)
+        }
+        let mut x = 0x0123_4567_89ab_cdefu64;
+        let edges = [0, 1, (1 << 32) - 1, 1 << 32, u64::MAX - 1, u64::MAX];
+        for &input in edges.iter() {
+            assert_eq!(integer_sqrt(input), reference(input), "sqrt of {}", input);
+        }
+        for _ in 0..10000 {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            assert_eq!(integer_sqrt(x), reference(x), "sqrt of {}", x);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn variant1_short_input() {
+        Hasher::new()
+            .with_variant(Variant::V1)
+            .hash(&[0u8; 42], &mut [0u8; 32]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn ways() {
+        // Interleaved hashes match hashing one input at a time
+        let inputs: Vec<Vec<u8>> = (0..MAX_WAYS as u8)
+            .map(|i| (0..76).map(|j| i ^ j).collect())
+            .collect();
+        let inputs: Vec<&[u8]> = inputs.iter().map(|input| input.as_ref()).collect();
+        for &variant in [Variant::V1, Variant::R].iter() {
+            for hasher in hashers() {
+                let mut hasher = hasher
+                    .with_profile(Profile::Lite)
+                    .with_variant(variant)
+                    .with_height(1806260);
+                let mut expected = [[0u8; 32]; MAX_WAYS];
+                for (input, output) in inputs.iter().zip(expected.iter_mut()) {
+                    hasher.hash(input, output);
+                }
+                for ways in 2..=MAX_WAYS {
+                    hasher.set_ways(ways);
+                    let mut outputs = vec![[0u8; 32]; ways];
+                    hasher.hash_ways(&inputs[..ways], &mut outputs);
+                    assert_eq!(outputs, &expected[..ways], "{:?}, {} ways", variant, ways);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn aes_rounds() {
+        // Small enough for Miri, unlike a whole hash
+        let block = [0x0011_2233_4455_6677, 0x8899_aabb_ccdd_eeff];
+        let key = [0x0f0e_0d0c_0b0a_0908, 0x0706_0504_0302_0100];
+        let mut text = [0u8; INIT_SIZE_BYTE];
+        for (i, byte) in text.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let exp_data = (0..240).map(|i| (i * 7) as u8).collect::<Vec<_>>();
+        let expected_round = [0xa10f_ac1c_cea2_5d52, 0x051d_5f57_affe_285c];
+        let expected_text = "e4b1cdf9e4ff4c31b54567177ea139a3761ea7298a4a39e1e0992aa94c4d0ee1\
+            415324f39a6ab95e187dccb0ef656f54c231aebae5e1522aa5e8d1d2326522a9\
+            39e33f5a3ccb3bad961219c388317fc3d706ec8f57a9dbb59e733e893db1f000\
+            e60ec31ab5c0bb08b5dbd6b53a3df4ff4f3956de18652a371414d11fa6951fd7";
+        assert_eq!(SoftAes::round(&block, &key), expected_round);
+        let mut rounds = text;
+        SoftAes::pseudo_rounds(&mut rounds, &exp_data);
+        assert_eq!(hex::encode(&rounds), expected_text);
+        #[cfg(target_arch = "x86_64")]
+        {
+            if aesni::available() {
+                assert_eq!(HardAes::round(&block, &key), expected_round);
+                let mut rounds = text;
+                HardAes::pseudo_rounds(&mut rounds, &exp_data);
+                assert_eq!(hex::encode(&rounds), expected_text);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn reuse() {
+        let mut hasher = Hasher::new();
+        let mut first = [0u8; 32];
+        let mut second = [0u8; 32];
+        let mut again = [0u8; 32];
+        hasher.hash(b"first input", &mut first);
+        hasher.hash(b"second input", &mut second);
+        hasher.hash(b"first input", &mut again);
+        assert_ne!(first, second);
+        assert_eq!(first, again);
+    }
+
     #[test]
+    #[cfg_attr(miri, ignore)]
     fn hash() {
         check_hash!(
             [
@@ -0,0 +1,64 @@
+//! Minimal hex encoding for Stratum blobs, targets and results.
+
+//
+// Constants
+//
+
+static HEX_CHARS: &[u8; 16] = b"0123456789abcdef";
+
+//
+// Functions
+//
+
+/// Decodes a hex string into bytes.
+pub fn decode(input: &str) -> Option<Vec<u8>> {
+    let input = input.as_bytes();
+    if input.len() % 2 != 0 {
+        return None;
+    }
+    input
+        .chunks(2)
+        .map(|pair| Some(nibble(pair[0])? << 4 | nibble(pair[1])?))
+        .collect()
+}
+
+/// Encodes bytes as a lowercase hex string.
+pub fn encode(input: &[u8]) -> String {
+    let mut output = String::with_capacity(input.len() * 2);
+    for byte in input {
+        output.push(HEX_CHARS[(byte >> 4) as usize] as char);
+        output.push(HEX_CHARS[(byte & 0x0f) as usize] as char);
+    }
+    output
+}
+
+fn nibble(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let bytes = [0x00, 0x01, 0x7f, 0x80, 0xab, 0xff];
+        assert_eq!(encode(&bytes), "00017f80abff");
+        assert_eq!(decode("00017F80abff").unwrap(), bytes.to_vec());
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(decode("abc").is_none());
+        assert!(decode("zz").is_none());
+    }
+}
@@ -1,10 +1,13 @@
-// Reference: https://github.com/noahdesu/xmonarch/blob/master/keccak.c
+//! Keccak, with the original padding CryptoNote uses rather than SHA-3's.
+//!
+//! Reference: https://github.com/noahdesu/xmonarch/blob/master/keccak.c
 
 //
 // Imports
 //
 
-use std::mem::size_of;
+#[cfg(all(target_arch = "x86_64", not(feature = "safe")))]
+use std::arch::x86_64::*;
 
 //
 // Type Aliases
@@ -16,6 +19,7 @@ type KeccakState = [u64; 25];
 // Constants
 //
 
+const STATE_SIZE: usize = 200;
 const HASH_DATA_AREA: usize = 136;
 const KECCAK_ROUNDS: usize = 24;
 
@@ -23,12 +27,6 @@ const KECCAK_ROUNDS: usize = 24;
 // Macros
 //
 
-macro_rules! rotl64 {
-    ($x:expr, $y:expr) => {
-        ($x << $y) | ($x >> (64 - $y))
-    };
-}
-
 macro_rules! keccak {
     (theta init [$bc:expr]; $i:expr => [st:$st:expr]) => {
         $bc[$i] = $st[$i] ^ $st[$i + 5] ^ $st[$i + 10] ^ $st[$i + 15] ^ $st[$i + 20];
@@ -38,7 +36,7 @@ macro_rules! keccak {
         $st[$i + 15] ^= $t; $st[$i + 20] ^= $t;
     };
     (theta full mix [$st:expr]; [bc:$bc:expr;t:$t:expr;i:$i:expr]) => {
-        $t = $bc[($i + 4) % 5] ^ rotl64!($bc[($i + 1) % 5], 1);
+        $t = $bc[($i + 4) % 5] ^ $bc[($i + 1) % 5].rotate_left(1);
         keccak!(theta mix [$st]; [t:$t;i:$i]);
     };
     (theta unroll mix [$st:expr]; [bc:$bc:expr;t:$t:expr]) => {
@@ -48,7 +46,7 @@ macro_rules! keccak {
     };
     (rho_pi [$st:expr]; [bc:$bc:expr;t:$t:ident;i:$i:expr]) => {
         $bc[0] = $st[$crate::keccak::PI[$i]];
-        $st[$crate::keccak::PI[$i]] = rotl64!($t, $crate::keccak::RHO[$i]);
+        $st[$crate::keccak::PI[$i]] = $t.rotate_left($crate::keccak::RHO[$i]);
         $t = $bc[0];
     };
     (rho_pi unroll5 [$st:expr]; [bc:$bc:expr;t:$t:ident;i:$i:expr]) => {
@@ -87,14 +85,143 @@ macro_rules! keccak {
     };
 }
 
+//
+// Structures
+//
+
+/// Streaming Keccak hasher.
+///
+/// Output is read straight from the state after the last permutation, so
+/// it can be up to 200 bytes long but never longer.
+#[derive(Clone)]
+pub struct Keccak {
+    st: KeccakState,
+    rate: usize,
+    output_len: usize,
+    buffer: [u8; STATE_SIZE],
+    buffered: usize,
+}
+
+//
+// Implementations
+//
+
+impl Keccak {
+    /// Keccak with `output_len` bytes of output and a capacity of twice that,
+    /// such as Keccak-256 for 32 bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics unless `output_len` is a non-zero multiple of 4 below 100.
+    pub fn new(output_len: usize) -> Keccak {
+        assert!(
+            output_len > 0 && output_len % 4 == 0 && output_len < STATE_SIZE / 2,
+            "Keccak output of {} bytes has no matching rate",
+            output_len
+        );
+        Keccak::with_rate(STATE_SIZE - 2 * output_len, output_len)
+    }
+
+    /// Keccak absorbing `rate` bytes per permutation, with `output_len` bytes
+    /// of output.
+    ///
+    /// # Panics
+    ///
+    /// Panics unless `rate` is a non-zero multiple of 8 below 200, and
+    /// `output_len` at most 200.
+    pub fn with_rate(rate: usize, output_len: usize) -> Keccak {
+        assert!(
+            rate > 0 && rate < STATE_SIZE && rate % 8 == 0,
+            "Keccak rate of {} bytes is invalid",
+            rate
+        );
+        assert!(
+            output_len <= STATE_SIZE,
+            "Keccak output is at most {} bytes",
+            STATE_SIZE
+        );
+        Keccak {
+            st: [0u64; 25],
+            rate,
+            output_len,
+            buffer: [0u8; STATE_SIZE],
+            buffered: 0,
+        }
+    }
+
+    /// Bytes absorbed per permutation.
+    pub fn rate(&self) -> usize {
+        self.rate
+    }
+
+    /// Bytes of output.
+    pub fn output_len(&self) -> usize {
+        self.output_len
+    }
+
+    /// Absorbs `data`.
+    pub fn update(&mut self, mut data: &[u8]) {
+        if self.buffered > 0 {
+            let taken = data.len().min(self.rate - self.buffered);
+            self.buffer[self.buffered..self.buffered + taken].copy_from_slice(&data[..taken]);
+            self.buffered += taken;
+            data = &data[taken..];
+            if self.buffered < self.rate {
+                return;
+            }
+            let buffer = self.buffer;
+            self.absorb(&buffer[..self.rate]);
+            self.buffered = 0;
+        }
+        let mut blocks = data.chunks_exact(self.rate);
+        for block in &mut blocks {
+            self.absorb(block);
+        }
+        let rest = blocks.remainder();
+        self.buffer[..rest.len()].copy_from_slice(rest);
+        self.buffered = rest.len();
+    }
+
+    /// Pads and absorbs the last block, writing the first `output_len` bytes
+    /// of the state to `output`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `output` is shorter than `output_len`.
+    pub fn finalize(mut self, output: &mut [u8]) {
+        let rate = self.rate;
+        self.buffer[self.buffered..rate]
+            .iter_mut()
+            .for_each(|byte| *byte = 0);
+        self.buffer[self.buffered] = 1;
+        self.buffer[rate - 1] |= 0x80;
+        let buffer = self.buffer;
+        self.absorb(&buffer[..rate]);
+        for (bytes, word) in output[..self.output_len].chunks_mut(8).zip(self.st.iter()) {
+            bytes.copy_from_slice(&word.to_le_bytes()[..bytes.len()]);
+        }
+    }
+
+    /// XORs a block of `rate` bytes into the state and permutes it.
+    fn absorb(&mut self, block: &[u8]) {
+        for (word, bytes) in self.st.iter_mut().zip(block.chunks_exact(8)) {
+            let mut le = [0u8; 8];
+            le.copy_from_slice(bytes);
+            *word ^= u64::from_le_bytes(le);
+        }
+        keccakf(&mut self.st, KECCAK_ROUNDS);
+    }
+}
+
 //
 // Functions
 //
 
-pub fn keccakf(st: &mut [u64], rounds: usize) {
+/// Permutes the state with `rounds` rounds of Keccak-f[1600], at most 24.
+pub fn keccakf(st: &mut KeccakState, rounds: usize) {
     let mut t: u64;
     let mut bc = [0u64; 5];
-    for round in 0..rounds {
+    for rc in RC.iter().take(rounds) {
         // Theta
         keccak!(theta init [bc]; 0 => [st:st]);
         keccak!(theta init [bc]; 1 => [st:st]);
@@ -108,49 +235,133 @@ pub fn keccakf(st: &mut [u64], rounds: usize) {
         // Chi
         keccak!(chi unroll [bc]; [st:st]);
         // Iota
-        st[0] ^= RC[round];
+        st[0] ^= rc;
+    }
+}
+
+/// Permutes each state with `rounds` rounds of Keccak-f[1600], at most 24.
+///
+/// With AVX2 the states go through four at a time, one per 64-bit lane.
+pub fn keccakf_ways(states: &mut [KeccakState], rounds: usize) {
+    #[cfg(all(target_arch = "x86_64", not(feature = "safe")))]
+    {
+        if states.len() > 1 && is_x86_feature_detected!("avx2") {
+            for chunk in states.chunks_mut(4) {
+                // A partial chunk still beats permuting its states one by one
+                let mut ways = [[0u64; 25]; 4];
+                ways[..chunk.len()].copy_from_slice(chunk);
+                unsafe { keccakf_x4(&mut ways, rounds) };
+                chunk.copy_from_slice(&ways[..chunk.len()]);
+            }
+            return;
+        }
+    }
+    for st in states.iter_mut() {
+        keccakf(st, rounds);
     }
 }
 
+/// Hashes `input` into all of `md`.
+///
+/// A 200-byte `md` receives the whole state after absorbing at the rate of
+/// Keccak-256, as CryptoNight needs. Other lengths pick the rate of the
+/// matching Keccak variant, as `Keccak::new` does.
+///
+/// # Panics
+///
+/// Panics unless `md` is 200 bytes long or a non-zero multiple of 4 below
+/// 100, such as 32 for Keccak-256.
 pub fn keccak(input: &[u8], md: &mut [u8]) {
-    let (mut in_len, md_len) = (input.len(), md.len());
-    let mut st: KeccakState = [0u64; 25];
-    let mut temp = [0u8; 144];
-    let rsiz = match size_of::<KeccakState>() {
-        sz if sz == md_len => HASH_DATA_AREA,
-        _ => 200 - (2 * md_len),
+    let mut keccak = match md.len() {
+        STATE_SIZE => Keccak::with_rate(HASH_DATA_AREA, STATE_SIZE),
+        len => Keccak::new(len),
     };
-    let rsizw = rsiz / 8;
-    let mut i_off = 0;
-    while in_len >= rsiz {
-        for i in 0..rsizw {
-            unsafe {
-                let in_ptr = input.as_ptr().offset(i_off as isize);
-                let in_ptr = in_ptr as *const u64;
-                st[i] ^= *in_ptr;
+    keccak.update(input);
+    keccak.finalize(md);
+}
+
+//
+// Private Functions
+//
+
+/// Keccak-f[1600] over four states, lane `i` of every state sharing one
+/// AVX2 register.
+#[cfg(all(target_arch = "x86_64", not(feature = "safe")))]
+#[target_feature(enable = "avx2")]
+unsafe fn keccakf_x4(states: &mut [KeccakState; 4], rounds: usize) {
+    macro_rules! xor {
+        ($a:expr, $b:expr) => {
+            _mm256_xor_si256($a, $b)
+        };
+    }
+    macro_rules! rotl {
+        ($v:expr, $n:literal) => {{
+            let v = $v;
+            _mm256_or_si256(
+                _mm256_slli_epi64::<$n>(v),
+                _mm256_srli_epi64::<{ 64 - $n }>(v),
+            )
+        }};
+    }
+    // Unrolled over the lanes PI and RHO walk through, keeping every index
+    // constant so the state never leaves registers
+    macro_rules! rho_pi {
+        ($a:ident => $b:ident; $($from:literal -> $to:literal <<< $n:literal),*) => {
+            $($b[$to] = rotl!($a[$from], $n);)*
+        };
+    }
+    let mut a = [_mm256_setzero_si256(); 25];
+    for (i, lane) in a.iter_mut().enumerate() {
+        *lane = _mm256_set_epi64x(
+            states[3][i] as i64,
+            states[2][i] as i64,
+            states[1][i] as i64,
+            states[0][i] as i64,
+        );
+    }
+    let mut b = [_mm256_setzero_si256(); 25];
+    for rc in RC.iter().take(rounds) {
+        // Theta
+        let mut c = [_mm256_setzero_si256(); 5];
+        for (x, c) in c.iter_mut().enumerate() {
+            *c = xor!(
+                xor!(xor!(a[x], a[x + 5]), xor!(a[x + 10], a[x + 15])),
+                a[x + 20]
+            );
+        }
+        for x in 0..5 {
+            let d = xor!(c[(x + 4) % 5], rotl!(c[(x + 1) % 5], 1));
+            for y in 0..5 {
+                a[x + 5 * y] = xor!(a[x + 5 * y], d);
+            }
+        }
+        // Rho Pi
+        b[0] = a[0];
+        rho_pi!(a => b;
+            1 -> 10 <<< 1, 10 -> 7 <<< 3, 7 -> 11 <<< 6, 11 -> 17 <<< 10,
+            17 -> 18 <<< 15, 18 -> 3 <<< 21, 3 -> 5 <<< 28, 5 -> 16 <<< 36,
+            16 -> 8 <<< 45, 8 -> 21 <<< 55, 21 -> 24 <<< 2, 24 -> 4 <<< 14,
+            4 -> 15 <<< 27, 15 -> 23 <<< 41, 23 -> 19 <<< 56, 19 -> 13 <<< 8,
+            13 -> 12 <<< 25, 12 -> 2 <<< 43, 2 -> 20 <<< 62, 20 -> 14 <<< 18,
+            14 -> 22 <<< 39, 22 -> 9 <<< 61, 9 -> 6 <<< 20, 6 -> 1 <<< 44
+        );
+        // Chi
+        for y in (0..25).step_by(5) {
+            for x in 0..5 {
+                a[y + x] = xor!(
+                    b[y + x],
+                    _mm256_andnot_si256(b[y + (x + 1) % 5], b[y + (x + 2) % 5])
+                );
             }
         }
-        keccakf(&mut st, KECCAK_ROUNDS);
-        in_len -= rsiz;
-        i_off += rsiz;
-    }
-    for i in 0..in_len {
-        temp[i] = input[i];
-    }
-    temp[in_len] = 1;
-    in_len += 1;
-    for i in 0..(rsiz - in_len) {
-        temp[in_len + i] = 0;
-    }
-    temp[rsiz - 1] |= 0x80;
-    for i in 0..rsizw {
-        st[i] ^= unsafe { *(temp.as_ptr() as *const u64).offset(i as isize) };
-    }
-    keccakf(&mut st, KECCAK_ROUNDS);
-    for i in 0..md_len {
-        unsafe {
-            let st_ptr = st.as_ptr() as *const u8;
-            md[i] = *st_ptr.offset(i as isize);
+        // Iota
+        a[0] = xor!(a[0], _mm256_set1_epi64x(*rc as i64));
+    }
+    for (i, lane) in a.iter().enumerate() {
+        let mut words = [0u64; 4];
+        _mm256_storeu_si256(words.as_mut_ptr() as *mut __m256i, *lane);
+        for (st, word) in states.iter_mut().zip(words.iter()) {
+            st[i] = *word;
         }
     }
 }
@@ -186,7 +397,7 @@ static RC: [u64; 24] = [
     0x8000000080008008,
 ];
 
-static RHO: [i32; 24] = [
+static RHO: [u32; 24] = [
     1, 3, 6, 10, 15, 21, 28, 36, 45, 55, 2, 14, 27, 41, 56, 8, 25, 43, 62, 18, 39, 61, 20, 44,
 ];
 
@@ -194,9 +405,14 @@ static PI: [usize; 24] = [
     10, 7, 11, 17, 18, 3, 5, 16, 8, 21, 24, 4, 15, 23, 19, 13, 12, 2, 20, 14, 22, 9, 6, 1,
 ];
 
+//
+// Tests
+//
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::hex;
 
     macro_rules! check_hash_result {
         ($($_in:expr => [$($_ref:expr),*]),*) => {{
@@ -226,4 +442,110 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn vectors() {
+        // Keccak reference digests for each rate, of "", "abc" and the bytes
+        // 0 to 199, which spans several blocks
+        let long = (0..200).map(|i| i as u8).collect::<Vec<_>>();
+        let vectors: [(usize, &[u8], &str); 10] = [
+            (28, b"", "f71837502ba8e10837bdd8d365adb85591895602fc552b48b7390abd"),
+            (28, b"abc", "c30411768506ebe1c2871b1ee2e87d38df342317300a9b97a95ec6a8"),
+            (
+                32,
+                b"",
+                "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470",
+            ),
+            (
+                32,
+                &long,
+                "bfb0aa97863e797943cf7c33bb7e880bb4543f3d2703c0923c6901c2af57b890",
+            ),
+            (
+                48,
+                b"",
+                "2c23146a63a29acf99e73b88f8c24eaa7dc60aa771780ccc006afbfa8fe2479b2dd2b21362337441ac12b515911957ff",
+            ),
+            (
+                48,
+                b"abc",
+                "f7df1165f033337be098e7d288ad6a2f74409d7a60b49c36642218de161b1f99f8c681e4afaf31a34db29fb763e3c28e",
+            ),
+            (
+                48,
+                &long,
+                "bdb00a13330573e75652bec64443b6ca9512b487fb7e1693540d04d55d68ff98b25cd30abd53bbbc27ba1c12195c479d",
+            ),
+            (
+                64,
+                b"",
+                "0eab42de4c3ceb9235fc91acffe746b29c29a8c366b7c60e4e67c466f36a4304c00fa9caf9d87976ba469bcbe06713b435f091ef2769fb160cdab33d3670680e",
+            ),
+            (
+                64,
+                b"abc",
+                "18587dc2ea106b9a1563e32b3312421ca164c7f1f07bc922a9c83d77cea3a1e5d0c69910739025372dc14ac9642629379540c17e2a65b19d77aa511a9d00bb96",
+            ),
+            (
+                64,
+                &long,
+                "f452d81b62b961f8023f8228cbe780379b36c49ddcef29e0dffb01a930c2cc53a694ed6ae3f0d224a2f1be55814a81841b90d56bcdf4a48a633f258a32dc14fc",
+            ),
+        ];
+        for &(len, input, expected) in vectors.iter() {
+            let mut output = vec![0u8; len];
+            keccak(input, &mut output);
+            assert_eq!(hex::encode(&output), expected, "Keccak-{}", len * 8);
+            // Fed in uneven pieces, straddling the block boundaries
+            let mut hasher = Keccak::new(len);
+            for piece in input.chunks(7) {
+                hasher.update(piece);
+            }
+            let mut streamed = vec![0u8; len];
+            hasher.finalize(&mut streamed);
+            assert_eq!(streamed, output, "streamed Keccak-{}", len * 8);
+        }
+    }
+
+    #[test]
+    fn ways() {
+        // Every count up to two chunks, the last one partial
+        for count in 1..=6 {
+            for &rounds in [12, 24].iter() {
+                let mut states = (0..count)
+                    .map(|way| {
+                        let mut st = [0u64; 25];
+                        for (i, word) in st.iter_mut().enumerate() {
+                            *word = ((way * 25 + i) as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+                        }
+                        st
+                    })
+                    .collect::<Vec<_>>();
+                let mut expected = states.clone();
+                for st in expected.iter_mut() {
+                    keccakf(st, rounds);
+                }
+                keccakf_ways(&mut states, rounds);
+                assert_eq!(states, expected, "{} states, {} rounds", count, rounds);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "no matching rate")]
+    fn output_too_long() {
+        keccak(b"abc", &mut [0u8; 100]);
+    }
+
+    #[test]
+    #[should_panic(expected = "no matching rate")]
+    fn output_not_whole_words() {
+        keccak(b"abc", &mut [0u8; 30]);
+    }
+
+    #[test]
+    #[should_panic(expected = "no matching rate")]
+    fn empty_output() {
+        Keccak::new(0);
+    }
 }
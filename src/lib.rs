@@ -7,23 +7,44 @@ extern crate json;
 // Modules
 //
 
+#[cfg(target_arch = "x86_64")]
+mod aesni;
+mod backoff;
 mod cryptonight;
+mod hex;
 mod keccak;
+mod memory;
 mod miner;
 mod oaes;
+mod random_math;
+mod registry;
 mod stratum;
+mod target;
+mod topology;
+mod transport;
+mod url;
+mod worker;
 
 //
 // Public API
 //
 
 pub mod algorithm {
-    pub use crate::cryptonight::cryptonight;
+    pub use crate::cryptonight::{cryptonight, Hasher, Profile, Variant, MAX_WAYS};
+    pub use crate::keccak::{keccakf, keccakf_ways, Keccak};
+    pub use crate::memory::Pages;
+    pub use crate::registry::{all, by_name, Algorithm, CryptoNight, DEFAULT_ALGORITHM};
 }
 
 pub mod protocol {
-    pub use crate::miner::{MinerConf, ValidatedMinerConf};
-    pub use crate::stratum::{StratumClient, StratumJob, StratumResponse};
+    pub use crate::miner::{ConfigError, MinerConf, ValidatedMinerConf, ValidatedPool};
+    pub use crate::stratum::{
+        ShareHandle, ShareStatus, StratumClient, StratumError, StratumJob, StratumResponse,
+    };
+    pub use crate::target::{Difficulty, Target, TargetError};
 }
 
-pub use crate::miner::{Miner, MinerConf};
+pub use crate::backoff::Backoff;
+pub use crate::miner::{ConfigError, Failover, Miner, MinerConf, Pool, ShareStats, StatsHandle};
+pub use crate::transport::Transport;
+pub use crate::url::{PoolUrl, PoolUrlError};
@@ -0,0 +1,263 @@
+//! Scratchpad memory, on huge pages where the OS provides them.
+//!
+//! A 2 MB scratchpad spans 512 regular pages, so random accesses miss the
+//! TLB almost every time. A single huge page covers all of it.
+
+//
+// Imports
+//
+
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+use std::slice;
+
+//
+// Constants
+//
+
+const BLOCK_SIZE: usize = 16;
+#[cfg(target_os = "linux")]
+const HUGE_PAGE_SIZE: usize = 2 << 20;
+
+//
+// Enumerations
+//
+
+/// The kind of pages backing a scratchpad, from least to most favourable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Pages {
+    /// Regular pages.
+    Regular,
+    /// Transparent huge pages, requested with `madvise`.
+    Transparent,
+    /// Reserved huge pages, mapped with `MAP_HUGETLB`.
+    Huge,
+}
+
+//
+// Structures
+//
+
+/// A zeroed buffer of 16-byte blocks.
+pub struct Scratchpad {
+    ptr: *mut [u64; 2],
+    len: usize,
+    /// Length of the memory mapping, 0 if the buffer is on the heap.
+    mapped: usize,
+    pages: Pages,
+}
+
+//
+// Implementations
+//
+
+impl Scratchpad {
+    /// Allocates `memory` bytes, preferring huge pages.
+    ///
+    /// Scratchpads smaller than a huge page stay on regular pages, rather
+    /// than taking a whole huge page each.
+    pub fn new(memory: usize) -> Scratchpad {
+        #[cfg(target_os = "linux")]
+        {
+            // Safe mode stays on the heap, where Miri can follow it
+            if !cfg!(feature = "safe") && memory >= HUGE_PAGE_SIZE {
+                let len = memory / BLOCK_SIZE;
+                if let Some(scratchpad) = map_huge(len).or_else(|| map_transparent(len)) {
+                    return scratchpad;
+                }
+            }
+        }
+        Scratchpad::regular(memory)
+    }
+
+    /// Allocates `memory` bytes on regular pages.
+    pub fn regular(memory: usize) -> Scratchpad {
+        let len = memory / BLOCK_SIZE;
+        let buffer = vec![[0u64; 2]; len].into_boxed_slice();
+        Scratchpad {
+            ptr: Box::into_raw(buffer) as *mut [u64; 2],
+            len,
+            mapped: 0,
+            pages: Pages::Regular,
+        }
+    }
+
+    /// The kind of pages obtained.
+    pub fn pages(&self) -> Pages {
+        self.pages
+    }
+}
+
+//
+// Trait implementations
+//
+
+// The scratchpad owns its memory, like a `Box` would
+unsafe impl Send for Scratchpad {}
+unsafe impl Sync for Scratchpad {}
+
+impl Deref for Scratchpad {
+    type Target = [[u64; 2]];
+
+    fn deref(&self) -> &[[u64; 2]] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl DerefMut for Scratchpad {
+    fn deref_mut(&mut self) -> &mut [[u64; 2]] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl Drop for Scratchpad {
+    fn drop(&mut self) {
+        unsafe {
+            if self.mapped == 0 {
+                drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+                    self.ptr, self.len,
+                )));
+            } else {
+                #[cfg(target_os = "linux")]
+                libc::munmap(self.ptr as *mut libc::c_void, self.mapped);
+            }
+        }
+    }
+}
+
+impl fmt::Display for Pages {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Pages::Regular => write!(f, "regular pages"),
+            Pages::Transparent => write!(f, "transparent huge pages"),
+            Pages::Huge => write!(f, "huge pages"),
+        }
+    }
+}
+
+//
+// Private Functions
+//
+
+/// Maps `len` blocks on reserved huge pages, if any are free.
+#[cfg(target_os = "linux")]
+fn map_huge(len: usize) -> Option<Scratchpad> {
+    let mapped = round_up(len * BLOCK_SIZE, HUGE_PAGE_SIZE);
+    let ptr = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            mapped,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_HUGETLB | libc::MAP_POPULATE,
+            -1,
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return None;
+    }
+    Some(Scratchpad {
+        ptr: ptr as *mut [u64; 2],
+        len,
+        mapped,
+        pages: Pages::Huge,
+    })
+}
+
+/// Maps `len` blocks aligned to a huge page and asks the kernel to back
+/// them with transparent huge pages.
+#[cfg(target_os = "linux")]
+fn map_transparent(len: usize) -> Option<Scratchpad> {
+    let size = len * BLOCK_SIZE;
+    // Over-allocate, then trim the mapping down to an aligned window
+    let padded = size + HUGE_PAGE_SIZE;
+    let ptr = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            padded,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return None;
+    }
+    let start = ptr as usize;
+    let aligned = round_up(start, HUGE_PAGE_SIZE);
+    let mapped = round_up(size, page_size());
+    unsafe {
+        if aligned > start {
+            libc::munmap(ptr, aligned - start);
+        }
+        let tail = aligned + mapped;
+        if start + padded > tail {
+            libc::munmap(tail as *mut libc::c_void, start + padded - tail);
+        }
+    }
+    let advised =
+        unsafe { libc::madvise(aligned as *mut libc::c_void, mapped, libc::MADV_HUGEPAGE) } == 0;
+    Some(Scratchpad {
+        ptr: aligned as *mut [u64; 2],
+        len,
+        mapped,
+        pages: if advised && transparent_enabled() {
+            Pages::Transparent
+        } else {
+            Pages::Regular
+        },
+    })
+}
+
+/// Whether the kernel honours `MADV_HUGEPAGE`.
+#[cfg(target_os = "linux")]
+fn transparent_enabled() -> bool {
+    std::fs::read_to_string("/sys/kernel/mm/transparent_hugepage/enabled")
+        .map(|mode| !mode.contains("[never]"))
+        .unwrap_or(false)
+}
+
+#[cfg(target_os = "linux")]
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+#[cfg(target_os = "linux")]
+fn round_up(value: usize, multiple: usize) -> usize {
+    value.div_ceil(multiple) * multiple
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scratchpad() {
+        let mut scratchpad = Scratchpad::new(1 << 20);
+        assert_eq!(scratchpad.len(), (1 << 20) / BLOCK_SIZE);
+        assert!(scratchpad.iter().all(|block| *block == [0, 0]));
+        let last = scratchpad.len() - 1;
+        scratchpad[0] = [1, 2];
+        scratchpad[last] = [3, 4];
+        assert_eq!(scratchpad[0], [1, 2]);
+        assert_eq!(scratchpad[last], [3, 4]);
+        // Too small to fill a huge page
+        assert_eq!(scratchpad.pages(), Pages::Regular);
+        #[cfg(target_os = "linux")]
+        {
+            if !cfg!(feature = "safe") {
+                let scratchpad = Scratchpad::new(HUGE_PAGE_SIZE);
+                if scratchpad.pages() != Pages::Huge {
+                    assert_eq!(scratchpad.as_ptr() as usize % HUGE_PAGE_SIZE, 0);
+                }
+                assert_eq!(Scratchpad::regular(HUGE_PAGE_SIZE).pages(), Pages::Regular);
+            }
+        }
+    }
+}
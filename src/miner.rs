@@ -2,34 +2,142 @@
 // Imports
 //
 
-use super::protocol::{StratumClient, StratumResponse};
-use std::sync::mpsc::{channel, Receiver};
+use super::algorithm::{self, Algorithm};
+use super::backoff::Backoff;
+use super::hex;
+use super::protocol::{ShareHandle, ShareStatus, StratumClient, StratumJob, StratumResponse};
+use super::topology::Topology;
+use super::transport::Transport;
+use super::url::{self, PoolUrl};
+use super::worker::{Share, WorkerPool};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+//
+// Constants
+//
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const SHARE_TIMEOUT: Duration = Duration::from_secs(30);
+
+//
+// Enumerations
+//
+
+/// Why a `MinerConf` failed validation.
+///
+/// Pool-specific variants name the pool by its URL.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigError {
+    NoPools,
+    MissingUser(String),
+    MissingPass(String),
+    InvalidFingerprint(String),
+    UnknownAlgorithm(String),
+    InvalidWays(usize),
+    InvalidThreads,
+    InvalidAffinity,
+    InvalidFailover,
+}
 
 //
 // Structures
 //
 
+/// A pool endpoint, optionally with credentials of its own.
+#[derive(Clone)]
 pub struct Pool {
     pub host: String,
     pub port: u16,
+    pub transport: Transport,
+    /// Hex SHA-256 fingerprint of the certificate a TLS pool must present.
+    pub fingerprint: Option<String>,
+    pub user: Option<String>,
+    pub pass: Option<String>,
+}
+
+/// When to give up on a pool and move on to the next one.
+#[derive(Clone, Debug)]
+pub struct Failover {
+    /// Failed connects or login errors before switching to the next pool.
+    pub max_failures: u32,
+    /// How often to check whether the primary pool is back while on a backup.
+    pub probe_interval: Duration,
 }
 
+/// Miner configuration.
+///
+/// Pools are tried in the order they were added, the first being the primary.
+/// Pools without credentials of their own use the miner-wide user and pass.
+#[derive(Default)]
 pub struct MinerConf {
     pub user: Option<String>,
     pub pass: Option<String>,
-    pub pool: Option<Pool>,
+    /// Name of the algorithm to mine, `DEFAULT_ALGORITHM` if unset.
+    pub algorithm: Option<String>,
+    /// Hashes each worker thread interleaves, 1 if unset.
+    pub ways: Option<usize>,
+    /// Worker threads, picked from the CPU topology if unset.
+    pub threads: Option<usize>,
+    /// CPUs the worker threads may run on, bit `n` standing for CPU `n`.
+    pub cpu_affinity: Option<u64>,
+    pub pools: Vec<Pool>,
+    pub backoff: Backoff,
+    pub failover: Failover,
 }
 
 #[derive(Clone)]
-pub struct ValidatedMinerConf {
+pub struct ValidatedPool {
+    pub host: String,
+    pub endpoint: String,
+    pub transport: Transport,
+    pub fingerprint: Option<Vec<u8>>,
     pub user: String,
     pub pass: String,
-    pub pool: String,
+}
+
+#[derive(Clone)]
+pub struct ValidatedMinerConf {
+    pub algorithm: &'static dyn Algorithm,
+    pub ways: usize,
+    pub threads: Option<usize>,
+    pub cpu_affinity: Option<u64>,
+    pub pools: Vec<ValidatedPool>,
+    pub backoff: Backoff,
+    pub failover: Failover,
+}
+
+/// Share submission counters.
+#[derive(Clone, Debug, Default)]
+pub struct ShareStats {
+    pub accepted: u64,
+    pub rejected: u64,
+    pub stale: u64,
+    pub timed_out: u64,
+    /// Rejection count per pool-reported reason.
+    pub reject_reasons: HashMap<String, u64>,
+}
+
+/// Shared view of a miner's share counters, readable while it mines.
+#[derive(Clone, Default)]
+pub struct StatsHandle {
+    stats: Arc<Mutex<ShareStats>>,
 }
 
 pub struct Miner {
+    algorithm: &'static dyn Algorithm,
     stratum: StratumClient,
     receiver: Receiver<StratumResponse>,
+    workers: WorkerPool,
+    shares: Receiver<Share>,
+    submitted: Vec<ShareHandle>,
+    miner_id: Option<String>,
+    job_id: Option<String>,
+    stats: StatsHandle,
 }
 
 //
@@ -37,32 +145,142 @@ pub struct Miner {
 //
 
 impl Miner {
-    /// Constructs a new `Miner`.
-    pub fn new(conf: MinerConf) -> Miner {
-        let conf = conf.validate();
+    /// Constructs a new `Miner`, failing if the configuration is invalid.
+    pub fn new(conf: MinerConf) -> Result<Miner, ConfigError> {
+        let conf = conf.validate()?;
         let (tx, rx) = channel();
+        let (share_tx, share_rx) = channel();
         let handlers = vec![tx];
-        Miner {
+        // Pools may switch to any advertised algorithm, so leave room in L3
+        // for the largest scratchpad
+        let largest = algorithm::all().map(|algorithm| algorithm.memory()).max();
+        let memory = largest.unwrap_or(conf.algorithm.memory()) * conf.ways;
+        let topology = Topology::detect();
+        let cpus = topology.schedule(memory, conf.threads, conf.cpu_affinity);
+        println!("Found {}, running {} threads", topology, cpus.len());
+        let workers = WorkerPool::new(share_tx, conf.algorithm, conf.ways, cpus);
+        Ok(Miner {
+            algorithm: conf.algorithm,
             stratum: StratumClient::new(conf, handlers),
             receiver: rx,
-        }
+            workers,
+            shares: share_rx,
+            submitted: Vec::new(),
+            miner_id: None,
+            job_id: None,
+            stats: StatsHandle::default(),
+        })
     }
 
     pub fn connect(&mut self) {
         self.stratum.connect();
-        self.stratum.login();
     }
 
+    /// Mines the jobs received from the pool, submitting any shares found.
     pub fn start(&mut self) {
         loop {
-            match self.receiver.recv() {
+            match self.receiver.recv_timeout(POLL_INTERVAL) {
                 Ok(StratumResponse::Login(miner_id, job)) => {
                     println!("Received miner id: {}", miner_id);
-                    println!("Received job with target {}", job.target);
+                    println!("Received job with difficulty {}", job.target.difficulty());
+                    self.miner_id = Some(miner_id);
+                    self.set_job(job);
+                }
+                Ok(StratumResponse::Job(job)) => {
+                    println!(
+                        "Received new job with difficulty {}",
+                        job.target.difficulty()
+                    );
+                    self.set_job(job);
+                }
+                Ok(StratumResponse::Disconnected) => {
+                    println!("Pausing until the pool is reachable again");
+                    self.workers.clear_job();
+                    self.miner_id = None;
+                    self.job_id = None;
+                }
+                Ok(StratumResponse::Error(err)) => println!("Stratum error: {}", err),
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => {
+                    println!("Unable to receive Stratum response!")
                 }
-                Ok(_) => println!("Invalid Stratum response!"),
-                Err(_) => println!("Unable to receive Stratum response!"),
             };
+            while let Ok(share) = self.shares.try_recv() {
+                self.submit(share);
+            }
+            self.stratum.expire_pending(SHARE_TIMEOUT);
+            let stats = &self.stats;
+            self.submitted.retain(|handle| match handle.try_status() {
+                Some(status) => {
+                    match status {
+                        ShareStatus::Accepted => println!("Share {} accepted", handle.id()),
+                        ShareStatus::Rejected(ref reason) => {
+                            println!("Share {} rejected: {}", handle.id(), reason)
+                        }
+                        ShareStatus::Stale => println!("Share {} was stale", handle.id()),
+                        ShareStatus::TimedOut => println!("Share {} timed out", handle.id()),
+                    }
+                    stats.record(&status);
+                    false
+                }
+                None => true,
+            });
+        }
+    }
+
+    /// Returns a handle to the share counters.
+    pub fn stats(&self) -> StatsHandle {
+        self.stats.clone()
+    }
+
+    fn set_job(&mut self, job: StratumJob) {
+        // Pools name the algorithm on each job when they switch on a fork
+        if let Some(ref name) = job.algorithm {
+            match algorithm::by_name(name) {
+                Some(algorithm) if algorithm.name() != self.algorithm.name() => {
+                    println!("Switching to algorithm {}", name);
+                    self.algorithm = algorithm;
+                }
+                Some(_) => (),
+                None => {
+                    println!(
+                        "Unable to mine job {}: unknown algorithm '{}'",
+                        job.job_id, name
+                    );
+                    self.workers.clear_job();
+                    self.job_id = None;
+                    return;
+                }
+            }
+        }
+        if self.workers.set_job(&job, self.algorithm) {
+            self.job_id = Some(job.job_id);
+        } else {
+            println!("Unable to decode job {}!", job.job_id);
+        }
+    }
+
+    fn submit(&mut self, share: Share) {
+        let miner_id = match self.miner_id {
+            Some(ref miner_id) => miner_id,
+            None => {
+                println!("Discarding share for job {}, not logged in", share.job_id);
+                self.stats.record(&ShareStatus::Stale);
+                return;
+            }
+        };
+        if self.job_id.as_ref() != Some(&share.job_id) {
+            println!("Discarding stale share for job {}", share.job_id);
+            self.stats.record(&ShareStatus::Stale);
+            return;
+        }
+        println!("Found share for job {}", share.job_id);
+        match self
+            .stratum
+            .share(miner_id, &share.job_id, share.nonce, &share.result)
+        {
+            Ok(handle) => self.submitted.push(handle),
+            Err(err) => println!("Unable to submit share: {}", err),
         }
     }
 
@@ -71,12 +289,75 @@ impl Miner {
     }
 }
 
-impl MinerConf {
-    pub fn with_pool<T: Into<String>>(mut self, host: T, port: u16) -> MinerConf {
-        self.pool = Some(Pool {
+impl StatsHandle {
+    /// Returns a copy of the current counters.
+    pub fn snapshot(&self) -> ShareStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    fn record(&self, status: &ShareStatus) {
+        let mut stats = self.stats.lock().unwrap();
+        match *status {
+            ShareStatus::Accepted => stats.accepted += 1,
+            ShareStatus::Rejected(ref reason) => {
+                stats.rejected += 1;
+                *stats.reject_reasons.entry(reason.clone()).or_insert(0) += 1;
+            }
+            ShareStatus::Stale => stats.stale += 1,
+            ShareStatus::TimedOut => stats.timed_out += 1,
+        }
+    }
+}
+
+impl Pool {
+    /// Constructs a new `Pool` using the miner-wide credentials.
+    pub fn new<T: Into<String>>(host: T, port: u16) -> Pool {
+        Pool {
             host: host.into(),
-            port: port,
-        });
+            port,
+            transport: Transport::Tcp,
+            fingerprint: None,
+            user: None,
+            pass: None,
+        }
+    }
+    pub fn with_transport(mut self, transport: Transport) -> Pool {
+        self.transport = transport;
+        self
+    }
+    /// Pins the certificate of a TLS pool in place of CA and hostname
+    /// validation, colons between bytes are allowed.
+    pub fn with_fingerprint<T: Into<String>>(mut self, fingerprint: T) -> Pool {
+        self.fingerprint = Some(fingerprint.into());
+        self
+    }
+    pub fn with_user<T: Into<String>>(mut self, user: T) -> Pool {
+        self.user = Some(user.into());
+        self
+    }
+    pub fn with_pass<T: Into<String>>(mut self, pass: T) -> Pool {
+        self.pass = Some(pass.into());
+        self
+    }
+}
+
+impl MinerConf {
+    /// Appends a pool, using the miner-wide credentials unless the URL has its own.
+    pub fn with_pool(mut self, url: PoolUrl) -> MinerConf {
+        self.pools.push(Pool::from(url));
+        self
+    }
+    /// Appends pools in failover order.
+    pub fn with_pools<I: IntoIterator<Item = Pool>>(mut self, pools: I) -> MinerConf {
+        self.pools.extend(pools);
+        self
+    }
+    pub fn with_backoff(mut self, backoff: Backoff) -> MinerConf {
+        self.backoff = backoff;
+        self
+    }
+    pub fn with_failover(mut self, failover: Failover) -> MinerConf {
+        self.failover = failover;
         self
     }
     pub fn with_user<T: Into<String>>(mut self, user: T) -> MinerConf {
@@ -87,13 +368,87 @@ impl MinerConf {
         self.pass = Some(pass.into());
         self
     }
-    fn validate(self) -> ValidatedMinerConf {
-        let pool = self.pool.unwrap();
-        ValidatedMinerConf {
-            user: self.user.unwrap(),
-            pass: self.pass.unwrap(),
-            pool: format!("{}:{}", pool.host, pool.port),
+    /// Selects the algorithm by name, such as `cn/r`.
+    pub fn with_algorithm<T: Into<String>>(mut self, algorithm: T) -> MinerConf {
+        self.algorithm = Some(algorithm.into());
+        self
+    }
+    /// Sets how many hashes each worker thread interleaves.
+    pub fn with_ways(mut self, ways: usize) -> MinerConf {
+        self.ways = Some(ways);
+        self
+    }
+    pub fn with_threads(mut self, threads: usize) -> MinerConf {
+        self.threads = Some(threads);
+        self
+    }
+    pub fn with_cpu_affinity(mut self, mask: u64) -> MinerConf {
+        self.cpu_affinity = Some(mask);
+        self
+    }
+    /// Checks the configuration and resolves per-pool credentials.
+    pub fn validate(self) -> Result<ValidatedMinerConf, ConfigError> {
+        if self.pools.is_empty() {
+            return Err(ConfigError::NoPools);
+        }
+        let name = self
+            .algorithm
+            .as_deref()
+            .unwrap_or(algorithm::DEFAULT_ALGORITHM);
+        let algorithm = algorithm::by_name(name)
+            .ok_or_else(|| ConfigError::UnknownAlgorithm(name.to_string()))?;
+        let ways = self.ways.unwrap_or(1);
+        if !(1..=algorithm::MAX_WAYS).contains(&ways) {
+            return Err(ConfigError::InvalidWays(ways));
         }
+        if self.threads == Some(0) {
+            return Err(ConfigError::InvalidThreads);
+        }
+        if self.cpu_affinity == Some(0) {
+            return Err(ConfigError::InvalidAffinity);
+        }
+        if self.failover.max_failures == 0 {
+            return Err(ConfigError::InvalidFailover);
+        }
+        let (user, pass) = (self.user, self.pass);
+        let pools = self
+            .pools
+            .into_iter()
+            .map(|pool| {
+                let endpoint = url::endpoint(&pool.host, pool.port);
+                let name = format!("{}://{}", pool.transport, endpoint);
+                let fingerprint = match pool.fingerprint {
+                    Some(fingerprint) => match hex::decode(&fingerprint.replace(':', "")) {
+                        Some(ref bytes) if bytes.len() == 32 => Some(bytes.clone()),
+                        _ => return Err(ConfigError::InvalidFingerprint(name)),
+                    },
+                    None => None,
+                };
+                Ok(ValidatedPool {
+                    user: match pool.user.or_else(|| user.clone()) {
+                        Some(user) => user,
+                        None => return Err(ConfigError::MissingUser(name)),
+                    },
+                    pass: match pool.pass.or_else(|| pass.clone()) {
+                        Some(pass) => pass,
+                        None => return Err(ConfigError::MissingPass(name)),
+                    },
+                    host: pool.host,
+                    endpoint,
+                    transport: pool.transport,
+                    fingerprint,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(ValidatedMinerConf {
+            algorithm,
+            ways,
+            threads: self.threads,
+            cpu_affinity: self.cpu_affinity,
+            pools,
+            backoff: self.backoff,
+            failover: self.failover,
+        })
     }
 }
 
@@ -101,12 +456,171 @@ impl MinerConf {
 // Trait implementations
 //
 
-impl Default for MinerConf {
-    fn default() -> MinerConf {
-        MinerConf {
-            pool: None,
-            user: None,
-            pass: None,
+impl Default for Failover {
+    fn default() -> Failover {
+        Failover {
+            max_failures: 3,
+            probe_interval: Duration::from_secs(300),
+        }
+    }
+}
+
+impl From<PoolUrl> for Pool {
+    fn from(url: PoolUrl) -> Pool {
+        Pool {
+            host: url.host,
+            port: url.port,
+            transport: url.transport,
+            fingerprint: url.fingerprint,
+            user: url.user,
+            pass: url.pass,
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::NoPools => write!(f, "no pool configured"),
+            ConfigError::MissingUser(ref pool) => write!(f, "no user configured for {}", pool),
+            ConfigError::MissingPass(ref pool) => write!(f, "no pass configured for {}", pool),
+            ConfigError::InvalidFingerprint(ref pool) => {
+                write!(
+                    f,
+                    "certificate fingerprint for {} is not 32 hex bytes",
+                    pool
+                )
+            }
+            ConfigError::UnknownAlgorithm(ref name) => write!(f, "unknown algorithm '{}'", name),
+            ConfigError::InvalidWays(ways) => write!(
+                f,
+                "{} ways requested, between 1 and {} are supported",
+                ways,
+                algorithm::MAX_WAYS
+            ),
+            ConfigError::InvalidThreads => write!(f, "at least one thread is needed"),
+            ConfigError::InvalidAffinity => write!(f, "CPU affinity mask selects no CPU"),
+            ConfigError::InvalidFailover => {
+                write!(f, "failover needs at least one failure per pool")
+            }
         }
     }
 }
+
+impl Error for ConfigError {}
+
+impl fmt::Display for ValidatedPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}://{}", self.transport, self.endpoint)
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate() {
+        let conf = MinerConf::default()
+            .with_user("wallet")
+            .with_pass("x")
+            .with_pools(vec![
+                Pool::new("primary", 3333),
+                Pool::new("::1", 4444).with_user("other"),
+            ])
+            .validate()
+            .unwrap();
+        assert_eq!(conf.pools[0].endpoint, "primary:3333");
+        assert_eq!(conf.pools[0].user, "wallet");
+        assert_eq!(conf.pools[1].endpoint, "[::1]:4444");
+        assert_eq!(conf.pools[1].user, "other");
+        assert_eq!(conf.pools[1].pass, "x");
+        assert_eq!(conf.algorithm.name(), algorithm::DEFAULT_ALGORITHM);
+        assert_eq!(conf.ways, 1);
+    }
+
+    #[test]
+    fn invalid() {
+        let pool = || Pool::new("pool", 3333);
+        let validate = |conf: MinerConf| conf.validate().err();
+        assert_eq!(validate(MinerConf::default()), Some(ConfigError::NoPools));
+        assert_eq!(
+            validate(MinerConf::default().with_pools(vec![pool()]).with_pass("x")),
+            Some(ConfigError::MissingUser(
+                "stratum+tcp://pool:3333".to_string()
+            ))
+        );
+        assert_eq!(
+            validate(
+                MinerConf::default()
+                    .with_pools(vec![pool()])
+                    .with_user("wallet")
+            ),
+            Some(ConfigError::MissingPass(
+                "stratum+tcp://pool:3333".to_string()
+            ))
+        );
+        let pinned = pool()
+            .with_transport(Transport::Tls)
+            .with_fingerprint("ab:cd");
+        assert_eq!(
+            validate(
+                MinerConf::default()
+                    .with_pools(vec![pinned])
+                    .with_user("wallet")
+                    .with_pass("x")
+            ),
+            Some(ConfigError::InvalidFingerprint(
+                "stratum+ssl://pool:3333".to_string()
+            ))
+        );
+        assert_eq!(
+            validate(
+                MinerConf::default()
+                    .with_pools(vec![pool()])
+                    .with_user("wallet")
+                    .with_pass("x")
+                    .with_algorithm("cn/3")
+            ),
+            Some(ConfigError::UnknownAlgorithm("cn/3".to_string()))
+        );
+        assert_eq!(
+            validate(
+                MinerConf::default()
+                    .with_pools(vec![pool()])
+                    .with_user("wallet")
+                    .with_pass("x")
+                    .with_ways(6)
+            ),
+            Some(ConfigError::InvalidWays(6))
+        );
+        assert_eq!(
+            validate(
+                MinerConf::default()
+                    .with_pools(vec![pool()])
+                    .with_user("wallet")
+                    .with_pass("x")
+                    .with_cpu_affinity(0)
+            ),
+            Some(ConfigError::InvalidAffinity)
+        );
+        let failover = Failover {
+            max_failures: 0,
+            ..Failover::default()
+        };
+        assert_eq!(
+            validate(
+                MinerConf::default()
+                    .with_pools(vec![pool()])
+                    .with_user("wallet")
+                    .with_pass("x")
+                    .with_failover(failover)
+            ),
+            Some(ConfigError::InvalidFailover)
+        );
+    }
+}
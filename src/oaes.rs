@@ -7,6 +7,8 @@
 
 const OAES_RKEY_LEN: usize = 4;
 const OAES_COL_LEN: usize = 4;
+const OAES_KEY_MAX_LEN: usize = 32;
+const OAES_EXP_DATA_LEN: usize = 240;
 
 static OAES_GF_8: [u8; 10] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36];
 
@@ -82,14 +84,15 @@ static OAES_SUB_BYTE_VALUE: [[u8; 16]; 16] = [
 //
 
 pub struct AesKey {
-    pub data: Vec<u8>,
+    pub data: [u8; OAES_KEY_MAX_LEN],
     pub data_len: usize,
-    pub exp_data: Vec<u8>,
+    pub exp_data: [u8; OAES_EXP_DATA_LEN],
     pub exp_data_len: usize,
     pub num_keys: usize,
     pub key_base: usize,
 }
 
+#[derive(Default)]
 pub struct AesContext {
     pub key: AesKey,
 }
@@ -99,14 +102,7 @@ pub struct AesContext {
 //
 
 fn oaes_word_rot_left(word: &mut [u8; OAES_COL_LEN]) {
-    let mut temp = [0u8; OAES_COL_LEN];
-    for i in 0..OAES_COL_LEN - 1 {
-        temp[i] = word[i + 1];
-    }
-    temp[OAES_COL_LEN - 1] = word[0];
-    for i in 0..OAES_COL_LEN {
-        word[i] = temp[i];
-    }
+    word.rotate_left(1);
 }
 
 fn oaes_sub_byte(byte: &mut u8) {
@@ -131,10 +127,8 @@ impl AesContext {
     fn import_key_data(&mut self, data: &[u8], data_len: usize) {
         self.key = AesKey::default();
         self.key.data_len = data_len;
-        self.key.data = vec![0u8; data_len];
-        for i in 0..usize::min(data.len(), data_len) {
-            self.key.data[i] = data[i];
-        }
+        let len = usize::min(data.len(), data_len);
+        self.key.data[..len].copy_from_slice(&data[..len]);
         self.key_expand();
     }
 
@@ -142,30 +136,23 @@ impl AesContext {
     fn key_expand(&mut self) {
         self.key.key_base = 8;
         self.key.num_keys = 15;
-        self.key.exp_data_len = 240;
-        self.key.exp_data = vec![0u8; self.key.exp_data_len];
-        for i in 0..self.key.data_len {
-            self.key.exp_data[i] = self.key.data[i];
-        }
+        self.key.exp_data_len = OAES_EXP_DATA_LEN;
+        let data_len = self.key.data_len;
+        self.key.exp_data[..data_len].copy_from_slice(&self.key.data[..data_len]);
         for i in 8..60 {
             let mut temp = [0u8; OAES_COL_LEN];
-            for col in 0..OAES_COL_LEN {
-                temp[col] = self.key.exp_data[((i - 1) * OAES_RKEY_LEN) + col];
-            }
+            let prev = (i - 1) * OAES_RKEY_LEN;
+            temp.copy_from_slice(&self.key.exp_data[prev..prev + OAES_COL_LEN]);
             if i % 8 == 0 {
                 oaes_word_rot_left(&mut temp);
-                for col in 0..OAES_COL_LEN {
-                    oaes_sub_byte(&mut temp[col]);
-                }
+                temp.iter_mut().for_each(oaes_sub_byte);
                 temp[0] ^= OAES_GF_8[i / self.key.key_base - 1];
             } else if i % self.key.key_base == 4 {
-                for col in 0..OAES_COL_LEN {
-                    oaes_sub_byte(&mut temp[col]);
-                }
+                temp.iter_mut().for_each(oaes_sub_byte);
             }
-            for j in 0..OAES_COL_LEN {
+            for (j, byte) in temp.iter().enumerate() {
                 let index = (i - self.key.key_base) * OAES_RKEY_LEN + j;
-                self.key.exp_data[i * OAES_RKEY_LEN + j] = self.key.exp_data[index] ^ temp[j];
+                self.key.exp_data[i * OAES_RKEY_LEN + j] = self.key.exp_data[index] ^ byte;
             }
         }
     }
@@ -174,9 +161,9 @@ impl AesContext {
 impl Default for AesKey {
     fn default() -> AesKey {
         AesKey {
-            data: Vec::new(),
+            data: [0u8; OAES_KEY_MAX_LEN],
             data_len: 0,
-            exp_data: Vec::new(),
+            exp_data: [0u8; OAES_EXP_DATA_LEN],
             exp_data_len: 0,
             num_keys: 0,
             key_base: 0,
@@ -184,13 +171,9 @@ impl Default for AesKey {
     }
 }
 
-impl Default for AesContext {
-    fn default() -> AesContext {
-        AesContext {
-            key: AesKey::default(),
-        }
-    }
-}
+//
+// Tests
+//
 
 #[cfg(test)]
 mod tests {
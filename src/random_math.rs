@@ -0,0 +1,341 @@
+//! CryptoNight-R random math programs.
+//!
+//! Every block height derives its own short program of integer operations,
+//! which runs once per main loop iteration. Generation follows
+//! `variant4_random_math.h` from the Monero sources, bit for bit.
+
+//
+// Imports
+//
+
+use blake::Blake;
+
+//
+// Constants
+//
+
+/// Minimal latency of the generated code, in cycles of the abstract CPU.
+const TOTAL_LATENCY: usize = 15 * 3;
+const NUM_INSTRUCTIONS_MIN: usize = 60;
+const NUM_INSTRUCTIONS_MAX: usize = 70;
+const ALU_COUNT_MUL: usize = 1;
+const ALU_COUNT: usize = 3;
+
+const OPCODE_BITS: u8 = 3;
+const DST_INDEX_BITS: u8 = 2;
+const SRC_INDEX_BITS: u8 = 3;
+
+/// Cycles each opcode takes on the abstract CPU, indexed by `Opcode`.
+const OP_LATENCY: [usize; 6] = [3, 2, 1, 2, 2, 1];
+/// Cycles each opcode takes on a theoretical ASIC, indexed by `Opcode`.
+const ASIC_OP_LATENCY: [usize; 6] = [3, 1, 1, 1, 1, 1];
+/// ALUs able to execute each opcode, indexed by `Opcode`.
+const OP_ALUS: [usize; 6] = [
+    ALU_COUNT_MUL,
+    ALU_COUNT,
+    ALU_COUNT,
+    ALU_COUNT,
+    ALU_COUNT,
+    ALU_COUNT,
+];
+
+//
+// Enumerations
+//
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Opcode {
+    Mul = 0,
+    Add = 1,
+    Sub = 2,
+    Ror = 3,
+    Rol = 4,
+    Xor = 5,
+}
+
+//
+// Structures
+//
+
+#[derive(Clone, Copy, Debug)]
+struct Instruction {
+    opcode: Opcode,
+    dst: usize,
+    src: usize,
+    constant: u32,
+}
+
+/// A random math program, derived from a block height.
+#[derive(Clone, Debug)]
+pub struct Program {
+    height: u64,
+    code: Vec<Instruction>,
+}
+
+/// Pseudo-random bytes, refilled by hashing the previous ones.
+struct Seed {
+    data: [u8; 32],
+    index: usize,
+}
+
+//
+// Implementations
+//
+
+impl Opcode {
+    fn is_rotation(self) -> bool {
+        self == Opcode::Ror || self == Opcode::Rol
+    }
+}
+
+impl Seed {
+    fn new(height: u64) -> Seed {
+        let mut data = [0u8; 32];
+        data[..8].copy_from_slice(&height.to_le_bytes());
+        data[20] = -38i8 as u8;
+        // Start past the end, so the first read hashes the seed
+        Seed { data, index: 32 }
+    }
+
+    /// Makes sure `needed` more bytes are available.
+    fn check(&mut self, needed: usize) {
+        if self.index + needed > self.data.len() {
+            let mut blake = Blake::new(256).unwrap();
+            blake.update(&self.data);
+            blake.finalise(&mut self.data);
+            self.index = 0;
+        }
+    }
+
+    fn byte(&mut self) -> u8 {
+        self.check(1);
+        self.index += 1;
+        self.data[self.index - 1]
+    }
+
+    fn word(&mut self) -> u32 {
+        self.check(4);
+        let mut word = [0u8; 4];
+        word.copy_from_slice(&self.data[self.index..self.index + 4]);
+        self.index += 4;
+        u32::from_le_bytes(word)
+    }
+}
+
+impl Program {
+    /// Generates the program for a block height.
+    pub fn generate(height: u64) -> Program {
+        let mut seed = Seed::new(height);
+        loop {
+            let (code, r8_used) = generate_code(&mut seed);
+            // Every program must read R8, and almost every first attempt does
+            if r8_used && code.len() >= NUM_INSTRUCTIONS_MIN && code.len() <= NUM_INSTRUCTIONS_MAX {
+                return Program { height, code };
+            }
+        }
+    }
+
+    /// The block height the program was derived from.
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    /// Runs the program on the registers.
+    ///
+    /// R0 to R3 are the variable registers, R4 to R8 are loaded from the
+    /// main loop state before every run.
+    #[inline]
+    pub fn execute(&self, r: &mut [u32; 9]) {
+        for op in &self.code {
+            let src = r[op.src];
+            let dst = &mut r[op.dst];
+            *dst = match op.opcode {
+                Opcode::Mul => dst.wrapping_mul(src),
+                Opcode::Add => dst.wrapping_add(src).wrapping_add(op.constant),
+                Opcode::Sub => dst.wrapping_sub(src),
+                Opcode::Ror => dst.rotate_right(src),
+                Opcode::Rol => dst.rotate_left(src),
+                Opcode::Xor => *dst ^ src,
+            };
+        }
+    }
+}
+
+//
+// Private Functions
+//
+
+/// Generates random code until the abstract CPU and the theoretical ASIC
+/// both need at least `TOTAL_LATENCY` cycles to run it.
+///
+/// Returns the code and whether it reads R8.
+fn generate_code(seed: &mut Seed) -> (Vec<Instruction>, bool) {
+    let mut code = Vec::with_capacity(NUM_INSTRUCTIONS_MAX);
+    let mut latency = [0usize; 9];
+    let mut asic_latency = [0usize; 9];
+
+    // Previous instruction and source value of R0 to R3, with R4 to R8
+    // treated as one value since they are constant:
+    // byte 0 is the destination value, byte 1 the opcode, byte 2 the source value
+    let mut inst_data: [u32; 9] = [0, 1, 2, 3, 0xFFFFFF, 0xFFFFFF, 0xFFFFFF, 0xFFFFFF, 0xFFFFFF];
+    let mut alu_busy = [[false; ALU_COUNT]; TOTAL_LATENCY + 1];
+    let mut rotated = [false; 4];
+    let mut rotate_count = 0;
+    let mut num_retries = 0;
+    let mut total_iterations = 0;
+    let mut r8_used = false;
+
+    while latency[..4].iter().any(|&l| l < TOTAL_LATENCY) && num_retries < 64 {
+        // Fail-safe to guarantee termination
+        total_iterations += 1;
+        if total_iterations > 256 {
+            break;
+        }
+
+        let c = seed.byte();
+        let opcode = match c & ((1 << OPCODE_BITS) - 1) {
+            0..=2 => Opcode::Mul,
+            3 => Opcode::Add,
+            4 => Opcode::Sub,
+            5 if (seed.byte() as i8) >= 0 => Opcode::Ror,
+            5 => Opcode::Rol,
+            _ => Opcode::Xor,
+        };
+        let dst = ((c >> OPCODE_BITS) & ((1 << DST_INDEX_BITS) - 1)) as usize;
+        let mut src =
+            ((c >> (OPCODE_BITS + DST_INDEX_BITS)) & ((1 << SRC_INDEX_BITS) - 1)) as usize;
+        let op = opcode as usize;
+
+        // ADD, SUB and XOR of a register with itself use R8 instead
+        if (opcode == Opcode::Add || opcode == Opcode::Sub || opcode == Opcode::Xor) && dst == src {
+            src = 8;
+        }
+
+        // Two rotations in a row are a single rotation
+        if opcode.is_rotation() && rotated[dst] {
+            continue;
+        }
+
+        // Repeating anything but MUL with the same source value can be optimized away
+        if opcode != Opcode::Mul
+            && (inst_data[dst] & 0xFFFF00) == ((op as u32) << 8) + ((inst_data[src] & 255) << 16)
+        {
+            continue;
+        }
+
+        // Find when and on which ALU the instruction can run
+        let mut next_latency = usize::max(latency[dst], latency[src]);
+        let mut alu_index = None;
+        while next_latency < TOTAL_LATENCY {
+            for i in (0..OP_ALUS[op]).rev() {
+                if alu_busy[next_latency][i] {
+                    continue;
+                }
+                // ADD takes two cycles on the ALU
+                if opcode == Opcode::Add && alu_busy[next_latency + 1][i] {
+                    continue;
+                }
+                // Rotations can't overlap
+                if opcode.is_rotation() && next_latency < rotate_count * OP_LATENCY[op] {
+                    continue;
+                }
+                alu_index = Some(i);
+                break;
+            }
+            if alu_index.is_some() {
+                break;
+            }
+            next_latency += 1;
+        }
+
+        // Don't leave a register unchanged for more than 7 cycles
+        if next_latency > latency[dst] + 7 {
+            continue;
+        }
+
+        next_latency += OP_LATENCY[op];
+        if next_latency > TOTAL_LATENCY {
+            num_retries += 1;
+            continue;
+        }
+        // A latency within bounds means the search above found an ALU
+        let alu_index = alu_index.unwrap();
+
+        if opcode.is_rotation() {
+            rotate_count += 1;
+        }
+        alu_busy[next_latency - OP_LATENCY[op]][alu_index] = true;
+        latency[dst] = next_latency;
+        asic_latency[dst] = usize::max(asic_latency[dst], asic_latency[src]) + ASIC_OP_LATENCY[op];
+        rotated[dst] = opcode.is_rotation();
+        inst_data[dst] = code.len() as u32 + ((op as u32) << 8) + ((inst_data[src] & 255) << 16);
+        if src == 8 {
+            r8_used = true;
+        }
+
+        let mut constant = 0;
+        if opcode == Opcode::Add {
+            alu_busy[next_latency - OP_LATENCY[op] + 1][alu_index] = true;
+            constant = seed.word();
+        }
+        code.push(Instruction {
+            opcode,
+            dst,
+            src,
+            constant,
+        });
+        if code.len() >= NUM_INSTRUCTIONS_MIN {
+            break;
+        }
+    }
+
+    // An ASIC extracts more parallelism, so pad with MUL and ROR until at
+    // least one register reaches the latency there too
+    let prev_code_size = code.len();
+    while code.len() < NUM_INSTRUCTIONS_MAX && asic_latency[..4].iter().all(|&l| l < TOTAL_LATENCY)
+    {
+        let mut min_idx = 0;
+        let mut max_idx = 0;
+        for i in 1..4 {
+            if asic_latency[i] < asic_latency[min_idx] {
+                min_idx = i;
+            }
+            if asic_latency[i] > asic_latency[max_idx] {
+                max_idx = i;
+            }
+        }
+        let pattern = [Opcode::Ror, Opcode::Mul, Opcode::Mul];
+        let opcode = pattern[(code.len() - prev_code_size) % 3];
+        latency[min_idx] = latency[max_idx] + OP_LATENCY[opcode as usize];
+        asic_latency[min_idx] = asic_latency[max_idx] + ASIC_OP_LATENCY[opcode as usize];
+        code.push(Instruction {
+            opcode,
+            dst: min_idx,
+            src: max_idx,
+            constant: 0,
+        });
+    }
+
+    (code, r8_used)
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn generate() {
+        for height in 1_800_000..1_800_200 {
+            let program = Program::generate(height);
+            assert_eq!(program.height(), height);
+            assert!(program.code.len() >= NUM_INSTRUCTIONS_MIN);
+            assert!(program.code.len() <= NUM_INSTRUCTIONS_MAX);
+            assert!(program.code.iter().any(|op| op.src == 8));
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//
+// Imports
+//
+
+use super::cryptonight::{Hasher, Profile, Variant};
+
+//
+// Constants
+//
+
+/// Every supported algorithm, by the name pools know it as.
+static ALGORITHMS: [CryptoNight; 7] = [
+    CryptoNight::new("cn/0", Profile::Standard, Variant::V0),
+    CryptoNight::new("cn/1", Profile::Standard, Variant::V1),
+    CryptoNight::new("cn/2", Profile::Standard, Variant::V2),
+    CryptoNight::new("cn/r", Profile::Standard, Variant::R),
+    CryptoNight::new("cn-lite/0", Profile::Lite, Variant::V0),
+    CryptoNight::new("cn-lite/1", Profile::Lite, Variant::V1),
+    CryptoNight::new("cn-heavy/0", Profile::Heavy, Variant::V0),
+];
+
+/// The algorithm used when none is configured.
+pub const DEFAULT_ALGORITHM: &str = "cn/0";
+
+//
+// Traits
+//
+
+/// A proof-of-work hash function the miner can search nonces with.
+pub trait Algorithm: Send + Sync {
+    /// The name pools know the algorithm as, such as `cn/r`.
+    fn name(&self) -> &'static str;
+
+    /// Scratchpad size in bytes.
+    fn memory(&self) -> usize;
+
+    /// Shortest input the algorithm can hash.
+    fn min_input_len(&self) -> usize {
+        0
+    }
+
+    /// Whether the hash depends on the block height of the job.
+    fn needs_height(&self) -> bool {
+        false
+    }
+
+    /// Configures `hasher` for this algorithm, reallocating its scratchpads
+    /// if their size differs.
+    fn prepare(&self, hasher: &mut Hasher);
+
+    /// Hashes `input` into the first 32 bytes of `output`.
+    ///
+    /// The hasher is reconfigured as needed, so one hasher can be shared by
+    /// every algorithm.
+    fn hash(&self, hasher: &mut Hasher, input: &[u8], height: u64, output: &mut [u8]);
+
+    /// Hashes each input into the matching output.
+    ///
+    /// Implementations may interleave as many hashes as the hasher has ways,
+    /// which requires exactly that many inputs.
+    fn hash_ways(
+        &self,
+        hasher: &mut Hasher,
+        inputs: &[&[u8]],
+        height: u64,
+        outputs: &mut [[u8; 32]],
+    ) {
+        for (input, output) in inputs.iter().zip(outputs.iter_mut()) {
+            self.hash(hasher, input, height, output);
+        }
+    }
+}
+
+//
+// Structures
+//
+
+/// A member of the CryptoNight family.
+#[derive(Clone, Copy, Debug)]
+pub struct CryptoNight {
+    name: &'static str,
+    profile: Profile,
+    variant: Variant,
+}
+
+//
+// Implementations
+//
+
+impl CryptoNight {
+    pub const fn new(name: &'static str, profile: Profile, variant: Variant) -> CryptoNight {
+        CryptoNight {
+            name,
+            profile,
+            variant,
+        }
+    }
+}
+
+//
+// Trait implementations
+//
+
+impl Algorithm for CryptoNight {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn memory(&self) -> usize {
+        self.profile.memory()
+    }
+
+    fn min_input_len(&self) -> usize {
+        match self.variant {
+            Variant::V1 => 43,
+            _ => 0,
+        }
+    }
+
+    fn needs_height(&self) -> bool {
+        self.variant == Variant::R
+    }
+
+    fn prepare(&self, hasher: &mut Hasher) {
+        hasher.set_profile(self.profile);
+        hasher.set_variant(self.variant);
+    }
+
+    fn hash(&self, hasher: &mut Hasher, input: &[u8], height: u64, output: &mut [u8]) {
+        self.prepare(hasher);
+        hasher.set_height(height);
+        hasher.hash(input, output);
+    }
+
+    fn hash_ways(
+        &self,
+        hasher: &mut Hasher,
+        inputs: &[&[u8]],
+        height: u64,
+        outputs: &mut [[u8; 32]],
+    ) {
+        self.prepare(hasher);
+        hasher.set_height(height);
+        hasher.hash_ways(inputs, outputs);
+    }
+}
+
+//
+// Functions
+//
+
+/// Looks up an algorithm by name.
+pub fn by_name(name: &str) -> Option<&'static dyn Algorithm> {
+    all().find(|algorithm| algorithm.name() == name)
+}
+
+/// Every supported algorithm.
+pub fn all() -> impl Iterator<Item = &'static dyn Algorithm> {
+    ALGORITHMS
+        .iter()
+        .map(|algorithm| algorithm as &'static dyn Algorithm)
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup() {
+        for name in &["cn/0", "cn/1", "cn/2", "cn/r", "cn-lite/1"] {
+            assert_eq!(by_name(name).map(|algorithm| algorithm.name()), Some(*name));
+        }
+        assert!(by_name("cn/3").is_none());
+        assert!(by_name(DEFAULT_ALGORITHM).is_some());
+        assert_eq!(by_name("cn-lite/1").unwrap().memory(), 1 << 20);
+        assert_eq!(by_name("cn/1").unwrap().min_input_len(), 43);
+        assert!(by_name("cn/r").unwrap().needs_height());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn shared_hasher() {
+        // Switching algorithms on one hasher matches dedicated hashers
+        let input = b"This is a test This is a test This is a test";
+        let mut hasher = Hasher::new();
+        for name in &["cn-lite/1", "cn/r", "cn/0"] {
+            let algorithm = by_name(name).unwrap();
+            let mut output = [0u8; 32];
+            algorithm.hash(&mut hasher, input, 1806260, &mut output);
+            let mut expected = [0u8; 32];
+            algorithm.hash(&mut Hasher::new(), input, 1806260, &mut expected);
+            assert_eq!(output, expected, "{}", name);
+        }
+    }
+}
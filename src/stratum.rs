@@ -4,18 +4,36 @@
 // Imports
 //
 
-use super::protocol::ValidatedMinerConf;
+use super::algorithm;
+use super::backoff::Backoff;
+use super::hex;
+use super::miner::Failover;
+use super::protocol::{Target, ValidatedMinerConf, ValidatedPool};
+use super::transport::{self, Connection};
 use json::{self, JsonValue};
-use std::io::{BufRead, BufReader, BufWriter, Write};
-use std::net::TcpStream;
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
+
+//
+// Constants
+//
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
 
 //
 // Type aliases
 //
 
 type MinerId = String;
+type PendingRequests = Arc<Mutex<HashMap<u64, PendingRequest>>>;
 
 //
 // Enumerations
@@ -24,37 +42,119 @@ type MinerId = String;
 #[derive(Clone)]
 pub enum StratumResponse {
     Login(MinerId, StratumJob),
-    Invalid,
+    Job(StratumJob),
+    Disconnected,
+    /// A message from the pool was malformed or reported an error.
+    Error(StratumError),
+}
+
+/// Errors of the Stratum connection and protocol.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StratumError {
+    /// The connection failed.
+    Io(ErrorKind, String),
+    /// A message was not valid JSON.
+    Json(String),
+    /// A message did not follow the Stratum protocol.
+    Protocol(String),
+    /// The pool answered a request with an error.
+    Pool { code: i64, message: String },
+    /// The client has no running connection thread.
+    NotConnected,
+}
+
+/// Why the connection thread closed a healthy connection.
+enum Disconnect {
+    /// The client was dropped.
+    Shutdown,
+    /// The primary pool is reachable again.
+    Failback,
+}
+
+/// What a received message means for the session.
+enum Received {
+    Message,
+    LoggedIn,
+    LoginRejected(StratumError),
+}
+
+/// Pool verdict on a submitted share.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ShareStatus {
+    Accepted,
+    Rejected(String),
+    Stale,
+    TimedOut,
 }
 
 //
 // Structures
 //
 
+/// A JSON-RPC 2.0 reply or pool-pushed notification.
 struct JsonRpcResponse {
     id: JsonValue,
     error: JsonValue,
     result: JsonValue,
+    method: JsonValue,
+    params: JsonValue,
 }
 
 #[derive(Clone)]
 pub struct StratumJob {
     pub blob: String,
     pub job_id: String,
-    pub target: String,
+    pub target: Target,
+    /// Block height of the job, which CryptoNight-R derives its program from.
+    pub height: Option<u64>,
+    /// Algorithm the pool assigned to the job, if it names one.
+    pub algorithm: Option<String>,
+}
+
+/// A submitted request awaiting its reply.
+struct PendingRequest {
+    submitted: Instant,
+    reply: Sender<ShareStatus>,
+}
+
+/// Reconnect bookkeeping of the connection thread.
+#[derive(Default)]
+struct Retries {
+    /// Consecutive failures, driving the backoff delay.
+    attempt: u32,
+    /// Consecutive failures on the current pool, driving failover.
+    failures: u32,
+}
+
+/// Handle to a submitted share, resolved once the pool replies.
+pub struct ShareHandle {
+    id: u64,
+    receiver: Receiver<ShareStatus>,
 }
 
-/// Stratum+TCP client.
+/// Stratum client over plaintext TCP or TLS.
+///
+/// The connection runs on its own thread, which logs in after every
+/// (re)connect and reconnects with exponential backoff whenever the
+/// connection drops. After repeated failures it fails over to the next
+/// configured pool, probing the primary pool until it can switch back.
 pub struct StratumClient {
     current_id: u64,
-    username: String,
-    password: String,
-    endpoint: String,
-    handlers: Vec<Sender<StratumResponse>>,
-    send_thread: Option<thread::JoinHandle<()>>,
-    recv_thread: Option<thread::JoinHandle<()>>,
+    session: Session,
+    thread: Option<thread::JoinHandle<()>>,
     sender: Option<Sender<String>>,
-    connected: bool,
+}
+
+/// Everything the connection thread needs to (re)establish a session.
+#[derive(Clone)]
+struct Session {
+    pools: Vec<ValidatedPool>,
+    /// Algorithms advertised at login, the configured one first.
+    algorithms: Vec<&'static str>,
+    backoff: Backoff,
+    failover: Failover,
+    handlers: Vec<Sender<StratumResponse>>,
+    pending: PendingRequests,
 }
 
 //
@@ -67,17 +167,29 @@ impl From<JsonValue> for JsonRpcResponse {
             id: val["id"].clone(),
             error: val["error"].clone(),
             result: val["result"].clone(),
+            method: val["method"].clone(),
+            params: val["params"].clone(),
         }
     }
 }
 
-impl<'a> From<&'a JsonRpcResponse> for StratumJob {
-    fn from(val: &JsonRpcResponse) -> Self {
-        Self {
-            blob: val.result["job"]["blob"].as_str().unwrap().to_owned(),
-            job_id: val.result["job"]["job_id"].as_str().unwrap().to_owned(),
-            target: val.result["job"]["target"].as_str().unwrap().to_owned(),
-        }
+impl StratumJob {
+    /// Parses a job object, as found in login replies and job notifications.
+    fn from_json(val: &JsonValue) -> Result<Self, StratumError> {
+        let field = |name: &str| {
+            val[name]
+                .as_str()
+                .ok_or_else(|| StratumError::Protocol(format!("job is missing its {}", name)))
+        };
+        Ok(Self {
+            blob: field("blob")?.to_owned(),
+            job_id: field("job_id")?.to_owned(),
+            target: field("target")?
+                .parse()
+                .map_err(|err| StratumError::Protocol(format!("invalid job target: {}", err)))?,
+            height: val["height"].as_u64(),
+            algorithm: val["algo"].as_str().map(str::to_owned),
+        })
     }
 }
 
@@ -86,65 +198,48 @@ impl StratumClient {
     pub fn new(conf: ValidatedMinerConf, handlers: Vec<Sender<StratumResponse>>) -> StratumClient {
         StratumClient {
             current_id: 1,
-            username: conf.user,
-            password: conf.pass,
-            endpoint: conf.pool,
-            handlers: handlers,
-            send_thread: None,
-            recv_thread: None,
-            connected: false,
+            session: Session {
+                pools: conf.pools,
+                algorithms: advertised_algorithms(conf.algorithm.name()),
+                backoff: conf.backoff,
+                failover: conf.failover,
+                handlers,
+                pending: Arc::new(Mutex::new(HashMap::new())),
+            },
+            thread: None,
             sender: None,
         }
     }
 
-    /// Connects to the pool.
+    /// Connects to the pool and authenticates.
     pub fn connect(&mut self) {
-        println!("Starting Stratum on stratum+tcp://{}", self.endpoint);
-        if let Ok(stream) = TcpStream::connect(&self.endpoint) {
-            // Create buffered reader and writer
-            let reader = BufReader::new(stream.try_clone().unwrap());
-            let writer = BufWriter::new(stream);
-
-            // Create send-receive channel
-            let (tx, rx) = channel();
-            self.sender = Some(tx);
-
-            // Create sender-thread
-            self.send_thread = Some(thread::spawn(move || {
-                handle_send(rx, writer);
-            }));
-
-            // Create receiver-thread
-            let handlers = self.handlers.clone();
-            self.recv_thread = Some(thread::spawn(move || handle_recv(reader, &handlers)));
-
-            // Signal that we are connected
-            self.connected = true;
-        } else {
-            println!("Stratum connection failed!");
-        }
-    }
-
-    /// Authenticates with the pool.
-    pub fn login(&mut self) {
-        let body = object! {
-            "jsonrpc" => "2.0",
-            "method" => "login",
-            "params" => object![
-                "login" => self.username.clone(),
-                "pass" => self.password.clone(),
-            ],
-            "id" => "login",
-        };
-        self.send(body);
+        println!("Starting Stratum on {}", self.session.pools[0]);
+        let (tx, rx) = channel();
+        self.sender = Some(tx);
+        let session = self.session.clone();
+        self.thread = Some(thread::spawn(move || run_session(session, rx)));
     }
 
-    pub fn share(&mut self) -> u64 {
+    /// Submits a share for the given job.
+    ///
+    /// The nonce is sent hex-encoded in little-endian byte order, as it
+    /// appears in the hashing blob.
+    pub fn share(
+        &mut self,
+        miner_id: &str,
+        job_id: &str,
+        nonce: u32,
+        result: &[u8; 32],
+    ) -> Result<ShareHandle, StratumError> {
         let id = self.get_id();
-        let miner_id = 0;
-        let job_id = 0;
-        let nonce = "foo";
-        let hash = "foo";
+        let (tx, rx) = channel();
+        let request = PendingRequest {
+            submitted: Instant::now(),
+            reply: tx,
+        };
+        self.session.pending.lock().unwrap().insert(id, request);
+        let nonce = hex::encode(&nonce.to_le_bytes());
+        let hash = hex::encode(result);
         let body = object! {
             "jsonrpc" => "2.0",
             "method" => "submit",
@@ -156,22 +251,37 @@ impl StratumClient {
             ],
             "id" => id,
         };
-        self.send(body);
-        id
+        if let Err(err) = self.send(body) {
+            self.session.pending.lock().unwrap().remove(&id);
+            return Err(err);
+        }
+        Ok(ShareHandle { id, receiver: rx })
+    }
+
+    /// Resolves requests that have waited longer than `timeout` as timed out.
+    pub fn expire_pending(&mut self, timeout: Duration) {
+        self.session.pending.lock().unwrap().retain(|_, request| {
+            if request.submitted.elapsed() < timeout {
+                return true;
+            }
+            request.reply.send(ShareStatus::TimedOut).ok();
+            false
+        });
     }
 
-    /// Blocks while the connection is alive.
+    /// Blocks while the client is running.
     pub fn join(self) {
-        self.send_thread.unwrap().join().unwrap();
+        self.thread.unwrap().join().unwrap();
     }
 
     /// Sends a JSON-RPC 2.0 object
-    fn send(&mut self, json: JsonValue) {
-        self.sender
-            .clone()
-            .unwrap()
-            .send(json::stringify(json))
-            .unwrap();
+    fn send(&mut self, json: JsonValue) -> Result<(), StratumError> {
+        match self.sender {
+            Some(ref sender) => sender
+                .send(json::stringify(json))
+                .map_err(|_| StratumError::NotConnected),
+            None => Err(StratumError::NotConnected),
+        }
     }
 
     /// Gets a fresh JSON-RPC 2.0 id
@@ -181,49 +291,471 @@ impl StratumClient {
     }
 }
 
+impl ShareHandle {
+    /// The JSON-RPC id the share was submitted with.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Blocks until the pool replies.
+    ///
+    /// Returns `None` if the connection closed before a reply arrived.
+    pub fn wait(&self) -> Option<ShareStatus> {
+        self.receiver.recv().ok()
+    }
+
+    /// Returns the pool verdict if it has arrived.
+    pub fn try_status(&self) -> Option<ShareStatus> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+//
+// Trait implementations
+//
+
+impl From<io::Error> for StratumError {
+    fn from(err: io::Error) -> StratumError {
+        StratumError::Io(err.kind(), err.to_string())
+    }
+}
+
+impl From<json::Error> for StratumError {
+    fn from(err: json::Error) -> StratumError {
+        StratumError::Json(err.to_string())
+    }
+}
+
+impl fmt::Display for StratumError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StratumError::Io(_, ref message) => write!(f, "{}", message),
+            StratumError::Json(ref message) => write!(f, "invalid JSON: {}", message),
+            StratumError::Protocol(ref message) => write!(f, "protocol error: {}", message),
+            StratumError::Pool { code, ref message } => {
+                write!(f, "pool error {}: {}", code, message)
+            }
+            StratumError::NotConnected => write!(f, "not connected"),
+        }
+    }
+}
+
+impl Error for StratumError {}
+
 //
 // Private Functions
 //
 
-fn handle_send(rx: Receiver<String>, mut writer: BufWriter<TcpStream>) {
+/// Keeps a session alive, reconnecting with backoff whenever it drops.
+fn run_session(session: Session, commands: Receiver<String>) {
+    let mut index = 0;
+    let mut retries = Retries::default();
     loop {
-        let command = rx.recv().unwrap();
-        write!(writer, "{}\n", command).unwrap();
-        writer.flush().unwrap();
+        let pool = &session.pools[index];
+        let result = transport::connect(pool, POLL_INTERVAL, WRITE_TIMEOUT)
+            .map_err(StratumError::from)
+            .and_then(|stream| {
+                println!("Connected to {}", pool);
+                let result = serve(stream, &session, index, &commands, &mut retries);
+                notify(&session, StratumResponse::Disconnected);
+                result
+            });
+        match result {
+            Ok(Disconnect::Shutdown) => return,
+            Ok(Disconnect::Failback) => {
+                println!("Primary pool is reachable again");
+                index = 0;
+                retries = Retries::default();
+                continue;
+            }
+            Err(err) => println!("Stratum connection to {} failed: {}", pool, err),
+        }
+        retries.failures += 1;
+        if retries.failures >= session.failover.max_failures && session.pools.len() > 1 {
+            index = (index + 1) % session.pools.len();
+            retries.failures = 0;
+            println!("Switching to pool {}", session.pools[index]);
+        }
+        let delay = session.backoff.delay(retries.attempt);
+        retries.attempt = retries.attempt.saturating_add(1);
+        println!("Reconnecting in {:.1}s", delay.as_secs_f64());
+        thread::sleep(delay);
     }
 }
 
-fn handle_recv(mut reader: BufReader<TcpStream>, handlers: &Vec<Sender<StratumResponse>>) {
+/// Logs in and relays messages until the connection drops.
+fn serve(
+    stream: Box<dyn Connection>,
+    session: &Session,
+    index: usize,
+    commands: &Receiver<String>,
+    retries: &mut Retries,
+) -> Result<Disconnect, StratumError> {
+    let mut reader = BufReader::new(stream);
+
+    abandon_pending(session, commands);
+    send_line(
+        reader.get_mut(),
+        &login_request(&session.pools[index], &session.algorithms),
+    )?;
+
+    let mut line = Vec::new();
+    let mut last_probe = Instant::now();
+    let mut probe: Option<Receiver<bool>> = None;
     loop {
-        let mut buf = String::new();
-        if reader.read_line(&mut buf).is_ok() {
-            if !buf.is_empty() {
-                let data = json::parse(&buf).unwrap();
-                let rpc_resp = JsonRpcResponse::from(data);
-                if !rpc_resp.error.is_null() {
-                    println!("Error: {}", rpc_resp.error["message"]);
-                    continue;
+        loop {
+            match commands.try_recv() {
+                Ok(command) => send_line(reader.get_mut(), &command)?,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(Disconnect::Shutdown),
+            }
+        }
+        if index != 0 {
+            match probe.as_ref().map(Receiver::try_recv) {
+                Some(Ok(true)) => return Ok(Disconnect::Failback),
+                Some(Ok(false)) | Some(Err(TryRecvError::Disconnected)) => probe = None,
+                Some(Err(TryRecvError::Empty)) => (),
+                None if last_probe.elapsed() >= session.failover.probe_interval => {
+                    last_probe = Instant::now();
+                    probe = Some(spawn_probe(&session.pools[0], &session.algorithms));
                 }
-                let stratum_resp = if !rpc_resp.result["job"].is_null() {
-                    let job = StratumJob::from(&rpc_resp);
-                    let miner_id = match rpc_resp.result["id"].as_str() {
-                        Some(val) => val.to_string(),
-                        None => {
-                            println!("Invalid miner id!");
-                            continue;
-                        }
-                    };
-                    StratumResponse::Login(miner_id, job)
-                } else {
-                    println!("Invalid Stratum response!");
-                    continue;
-                };
-                for handler in handlers {
-                    handler.send(stratum_resp.clone()).unwrap();
+                None => (),
+            }
+        }
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => {
+                let err = io::Error::new(ErrorKind::UnexpectedEof, "closed by pool");
+                return Err(err.into());
+            }
+            Ok(_) if line.ends_with(b"\n") => {
+                let received = handle_recv(&String::from_utf8_lossy(&line), session);
+                line.clear();
+                match received {
+                    Ok(Received::Message) => (),
+                    Ok(Received::LoggedIn) => *retries = Retries::default(),
+                    // Without a session there is nothing to mine, so reconnect
+                    Ok(Received::LoginRejected(err)) => return Err(err),
+                    Err(err) => notify(session, StratumResponse::Error(err)),
+                }
+            }
+            Ok(_) => (),
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => (),
+            Err(ref err) if err.kind() == ErrorKind::TimedOut => (),
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Resolves the shares of the previous connection as stale, whether they
+/// were still queued or awaiting a reply that can no longer arrive.
+fn abandon_pending(session: &Session, commands: &Receiver<String>) {
+    let mut pending = session.pending.lock().unwrap();
+    while commands.try_recv().is_ok() {}
+    for (_, request) in pending.drain() {
+        request.reply.send(ShareStatus::Stale).ok();
+    }
+}
+
+/// Probes a pool on a thread of its own, so the session keeps relaying
+/// jobs and shares meanwhile.
+fn spawn_probe(pool: &ValidatedPool, algorithms: &[&'static str]) -> Receiver<bool> {
+    let (tx, rx) = channel();
+    let pool = pool.clone();
+    let algorithms = algorithms.to_vec();
+    thread::spawn(move || tx.send(probe(&pool, &algorithms)).ok());
+    rx
+}
+
+/// Checks whether a pool accepts a login over its configured transport.
+fn probe(pool: &ValidatedPool, algorithms: &[&str]) -> bool {
+    let stream = match transport::connect(pool, PROBE_TIMEOUT, WRITE_TIMEOUT) {
+        Ok(stream) => stream,
+        Err(_) => return false,
+    };
+    let mut reader = BufReader::new(stream);
+    if send_line(reader.get_mut(), &login_request(pool, algorithms)).is_err() {
+        return false;
+    }
+    let deadline = Instant::now() + PROBE_TIMEOUT;
+    let mut line = String::new();
+    while Instant::now() < deadline {
+        line.clear();
+        match reader.read_line(&mut line) {
+            Ok(0) | Err(_) => return false,
+            Ok(_) => match json::parse(&line) {
+                Ok(ref reply) if reply["id"] == "login" => {
+                    return reply["error"].is_null() && !reply["result"]["job"].is_null();
                 }
+                _ => (),
+            },
+        }
+    }
+    false
+}
+
+fn send_line<W: Write>(writer: &mut W, line: &str) -> io::Result<()> {
+    writer.write_all(format!("{}\n", line).as_bytes())?;
+    writer.flush()
+}
+
+/// Builds the login request, advertising `algorithms` to pools that
+/// support the algo extension.
+fn login_request(pool: &ValidatedPool, algorithms: &[&str]) -> String {
+    let body = object! {
+        "jsonrpc" => "2.0",
+        "method" => "login",
+        "params" => object![
+            "login" => pool.user.clone(),
+            "pass" => pool.pass.clone(),
+            "algo" => algorithms,
+        ],
+        "id" => "login",
+    };
+    json::stringify(body)
+}
+
+/// Lists every supported algorithm, `preferred` first.
+fn advertised_algorithms(preferred: &'static str) -> Vec<&'static str> {
+    let others = algorithm::all()
+        .map(|algorithm| algorithm.name())
+        .filter(|&name| name != preferred);
+    Some(preferred).into_iter().chain(others).collect()
+}
+
+/// Dispatches a received line, returning what it means for the session.
+fn handle_recv(buf: &str, session: &Session) -> Result<Received, StratumError> {
+    let rpc_resp = JsonRpcResponse::from(json::parse(buf)?);
+    if let Some(id) = rpc_resp.id.as_u64() {
+        let request = session.pending.lock().unwrap().remove(&id);
+        return match request {
+            Some(request) => {
+                request.reply.send(share_status(&rpc_resp)).ok();
+                Ok(Received::Message)
+            }
+            None => Err(StratumError::Protocol(format!(
+                "unexpected reply to request {}",
+                id
+            ))),
+        };
+    }
+    if !rpc_resp.error.is_null() {
+        let err = pool_error(&rpc_resp.error);
+        if rpc_resp.id == "login" {
+            return Ok(Received::LoginRejected(err));
+        }
+        return Err(err);
+    }
+    let (stratum_resp, received) = if rpc_resp.method == "job" {
+        let job = StratumJob::from_json(&rpc_resp.params)?;
+        (StratumResponse::Job(job), Received::Message)
+    } else if !rpc_resp.result["job"].is_null() {
+        let job = StratumJob::from_json(&rpc_resp.result["job"])?;
+        let miner_id = rpc_resp.result["id"]
+            .as_str()
+            .ok_or_else(|| StratumError::Protocol("login reply is missing the miner id".into()))?;
+        (
+            StratumResponse::Login(miner_id.to_string(), job),
+            Received::LoggedIn,
+        )
+    } else {
+        return Err(StratumError::Protocol(format!(
+            "unexpected message: {}",
+            buf.trim_end()
+        )));
+    };
+    notify(session, stratum_resp);
+    Ok(received)
+}
+
+/// Sends a response to every handler.
+fn notify(session: &Session, response: StratumResponse) {
+    for handler in &session.handlers {
+        handler.send(response.clone()).ok();
+    }
+}
+
+/// Converts a JSON-RPC error object.
+fn pool_error(error: &JsonValue) -> StratumError {
+    StratumError::Pool {
+        code: error["code"].as_i64().unwrap_or(0),
+        message: error["message"]
+            .as_str()
+            .unwrap_or("unknown error")
+            .to_string(),
+    }
+}
+
+fn share_status(rpc_resp: &JsonRpcResponse) -> ShareStatus {
+    let reason = if !rpc_resp.error.is_null() {
+        match pool_error(&rpc_resp.error) {
+            StratumError::Pool { message, .. } => message,
+            err => err.to_string(),
+        }
+    } else {
+        match rpc_resp.result["status"].as_str() {
+            Some("OK") => return ShareStatus::Accepted,
+            Some(status) => status.to_string(),
+            None => "Invalid submit response".to_string(),
+        }
+    };
+    // Pools report shares for superseded jobs as expired or stale
+    let lowercase = reason.to_lowercase();
+    if lowercase.contains("expired") || lowercase.contains("stale") {
+        ShareStatus::Stale
+    } else {
+        ShareStatus::Rejected(reason)
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::Transport;
+
+    fn reply(line: &str) -> JsonRpcResponse {
+        JsonRpcResponse::from(json::parse(line).unwrap())
+    }
+
+    #[test]
+    fn submit_status() {
+        let accepted = reply(r#"{"id":2,"jsonrpc":"2.0","error":null,"result":{"status":"OK"}}"#);
+        let rejected = reply(
+            r#"{"id":3,"jsonrpc":"2.0","error":{"code":-1,"message":"Low difficulty share"}}"#,
+        );
+        let stale =
+            reply(r#"{"id":4,"jsonrpc":"2.0","error":{"code":-1,"message":"Block expired"}}"#);
+        assert_eq!(share_status(&accepted), ShareStatus::Accepted);
+        assert_eq!(
+            share_status(&rejected),
+            ShareStatus::Rejected("Low difficulty share".to_string())
+        );
+        assert_eq!(share_status(&stale), ShareStatus::Stale);
+    }
+
+    #[test]
+    fn login_algorithms() {
+        let algorithms = advertised_algorithms("cn/r");
+        assert_eq!(algorithms[0], "cn/r");
+        assert_eq!(algorithms.iter().filter(|&&name| name == "cn/r").count(), 1);
+        assert!(algorithms.contains(&"cn/0"));
+        let pool = ValidatedPool {
+            host: "pool".to_string(),
+            endpoint: "pool:3333".to_string(),
+            transport: Transport::Tcp,
+            fingerprint: None,
+            user: "wallet".to_string(),
+            pass: "x".to_string(),
+        };
+        let login = json::parse(&login_request(&pool, &algorithms)).unwrap();
+        assert_eq!(login["params"]["login"], "wallet");
+        assert_eq!(login["params"]["algo"][0], "cn/r");
+        assert_eq!(login["params"]["algo"].len(), algorithms.len());
+    }
+
+    #[test]
+    fn malformed() {
+        let (tx, rx) = channel();
+        let session = Session {
+            pools: Vec::new(),
+            algorithms: Vec::new(),
+            backoff: Backoff::default(),
+            failover: Failover::default(),
+            handlers: vec![tx],
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        };
+        let recv = |line: &str| handle_recv(line, &session).err();
+        assert!(matches!(recv("{\"id\":"), Some(StratumError::Json(_))));
+        assert_eq!(
+            recv(r#"{"method":"job","params":{"blob":"00","target":"ffffffff"}}"#),
+            Some(StratumError::Protocol(
+                "job is missing its job_id".to_string()
+            ))
+        );
+        assert_eq!(
+            recv(r#"{"id":7,"result":{"status":"OK"}}"#),
+            Some(StratumError::Protocol(
+                "unexpected reply to request 7".to_string()
+            ))
+        );
+        assert!(rx.try_recv().is_err());
+        assert!(recv(
+            r#"{"method":"job","params":{"blob":"00","job_id":"1","target":"ffffffff","height":1806260,"algo":"cn/r"}}"#
+        )
+        .is_none());
+        match rx.try_recv() {
+            Ok(StratumResponse::Job(job)) => {
+                assert_eq!(job.height, Some(1806260));
+                assert_eq!(job.algorithm.as_deref(), Some("cn/r"));
             }
-        } else {
-            println!("Unable to parse Stratum response!");
+            _ => panic!("expected a job"),
         }
     }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn probe_login() {
+        use std::net::TcpListener;
+
+        // A pool that answers one login with `reply`
+        let pool = |reply: &'static str| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let endpoint = listener.local_addr().unwrap().to_string();
+            thread::spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut login = String::new();
+                reader.read_line(&mut login).unwrap();
+                send_line(reader.get_mut(), reply).unwrap();
+            });
+            ValidatedPool {
+                host: "127.0.0.1".to_string(),
+                endpoint,
+                transport: Transport::Tcp,
+                fingerprint: None,
+                user: "wallet".to_string(),
+                pass: "x".to_string(),
+            }
+        };
+        let accepted = pool(
+            r#"{"id":"login","error":null,"result":{"id":"1","job":{"blob":"00","job_id":"1","target":"ffffffff"}}}"#,
+        );
+        assert!(probe(&accepted, &["cn/0"]));
+        let rejected = pool(r#"{"id":"login","error":{"code":-1,"message":"Unauthenticated"}}"#);
+        assert!(!probe(&rejected, &["cn/0"]));
+        let mut closed = accepted.clone();
+        closed.endpoint = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        assert!(!probe(&closed, &["cn/0"]));
+    }
+
+    #[test]
+    fn abandoned() {
+        let session = Session {
+            pools: Vec::new(),
+            algorithms: Vec::new(),
+            backoff: Backoff::default(),
+            failover: Failover::default(),
+            handlers: Vec::new(),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        };
+        let (command_tx, commands) = channel();
+        let (reply, status) = channel();
+        let request = PendingRequest {
+            submitted: Instant::now(),
+            reply,
+        };
+        session.pending.lock().unwrap().insert(2, request);
+        command_tx.send("{\"id\":2}".to_string()).unwrap();
+        abandon_pending(&session, &commands);
+        assert_eq!(status.try_recv(), Ok(ShareStatus::Stale));
+        assert!(commands.try_recv().is_err());
+        assert!(session.pending.lock().unwrap().is_empty());
+    }
 }
@@ -0,0 +1,192 @@
+//! Share targets and difficulties.
+//!
+//! Pools send the target either in the 4-byte compact form, where
+//! `difficulty = 0xFFFFFFFF / target`, or as a full 8-byte value compared
+//! against the most significant 64 bits of the hash. Both are hex-encoded
+//! little-endian integers.
+
+//
+// Imports
+//
+
+use super::hex;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+//
+// Enumerations
+//
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TargetError {
+    InvalidHex,
+    InvalidLength(usize),
+    Zero,
+}
+
+//
+// Structures
+//
+
+/// Share difficulty.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Difficulty(u64);
+
+/// Share target.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Target {
+    value: u64,
+    difficulty: Difficulty,
+}
+
+//
+// Implementations
+//
+
+impl Difficulty {
+    /// Constructs a new `Difficulty`, clamped to at least 1.
+    pub fn new(difficulty: u64) -> Difficulty {
+        Difficulty(u64::max(difficulty, 1))
+    }
+
+    /// The numeric difficulty.
+    pub fn value(self) -> u64 {
+        self.0
+    }
+
+    /// The 64-bit target a hash must stay below to reach this difficulty.
+    pub fn target(self) -> Target {
+        Target {
+            value: u64::MAX / self.0,
+            difficulty: self,
+        }
+    }
+}
+
+impl Target {
+    /// Decodes the 4-byte compact form.
+    pub fn from_compact(compact: u32) -> Result<Target, TargetError> {
+        match compact {
+            0 => Err(TargetError::Zero),
+            compact => Ok(Difficulty::new(u64::from(u32::MAX / compact)).target()),
+        }
+    }
+
+    /// Decodes the full 8-byte form.
+    pub fn from_value(value: u64) -> Result<Target, TargetError> {
+        match value {
+            0 => Err(TargetError::Zero),
+            value => Ok(Target {
+                value,
+                difficulty: Difficulty::new(u64::MAX / value),
+            }),
+        }
+    }
+
+    /// The 64-bit value compared against the hash.
+    pub fn value(&self) -> u64 {
+        self.value
+    }
+
+    /// The difficulty this target represents.
+    pub fn difficulty(&self) -> Difficulty {
+        self.difficulty
+    }
+
+    /// Checks whether a final cryptonight hash meets this target.
+    #[inline]
+    pub fn meets(&self, hash: &[u8; 32]) -> bool {
+        let mut top = [0u8; 8];
+        top.copy_from_slice(&hash[24..]);
+        u64::from_le_bytes(top) < self.value
+    }
+}
+
+//
+// Trait implementations
+//
+
+impl FromStr for Target {
+    type Err = TargetError;
+
+    fn from_str(s: &str) -> Result<Target, TargetError> {
+        let bytes = hex::decode(s).ok_or(TargetError::InvalidHex)?;
+        match bytes.len() {
+            4 => {
+                let mut compact = [0u8; 4];
+                compact.copy_from_slice(&bytes);
+                Target::from_compact(u32::from_le_bytes(compact))
+            }
+            8 => {
+                let mut value = [0u8; 8];
+                value.copy_from_slice(&bytes);
+                Target::from_value(u64::from_le_bytes(value))
+            }
+            len => Err(TargetError::InvalidLength(len)),
+        }
+    }
+}
+
+impl fmt::Display for Difficulty {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Display for TargetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TargetError::InvalidHex => write!(f, "target is not valid hex"),
+            TargetError::InvalidLength(len) => {
+                write!(f, "target must be 4 or 8 bytes, got {}", len)
+            }
+            TargetError::Zero => write!(f, "target is zero"),
+        }
+    }
+}
+
+impl Error for TargetError {}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash_with_top(top: u64) -> [u8; 32] {
+        let mut hash = [0xffu8; 32];
+        hash[24..].copy_from_slice(&top.to_le_bytes());
+        hash
+    }
+
+    #[test]
+    fn compact() {
+        let target: Target = "b88d0600".parse().unwrap();
+        assert_eq!(target.difficulty().value(), 10000);
+        assert_eq!(target.value(), u64::MAX / 10000);
+    }
+
+    #[test]
+    fn full() {
+        let target: Target = "471b47acc5a70000".parse().unwrap();
+        assert_eq!(target.difficulty().value(), 100000);
+        assert_eq!(target.value(), 0x0000_a7c5_ac47_1b47);
+    }
+
+    #[test]
+    fn meets() {
+        let target = Difficulty::new(10000).target();
+        assert!(target.meets(&hash_with_top(target.value() - 1)));
+        assert!(!target.meets(&hash_with_top(target.value())));
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!("00000000".parse::<Target>(), Err(TargetError::Zero));
+        assert_eq!("abc".parse::<Target>(), Err(TargetError::InvalidHex));
+        assert_eq!("abcd".parse::<Target>(), Err(TargetError::InvalidLength(2)));
+    }
+}
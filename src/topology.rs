@@ -0,0 +1,278 @@
+//! CPU topology, thread count and thread placement.
+//!
+//! Each hash thread hammers its own scratchpad, so it runs fastest when the
+//! scratchpad stays in L3 and no other thread shares its core.
+
+//
+// Imports
+//
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+//
+// Structures
+//
+
+/// Online CPUs and the L3 cache they share.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Topology {
+    /// Logical CPUs, grouped by physical core.
+    cores: Vec<Vec<usize>>,
+    /// Total L3 cache in bytes, if known.
+    l3: Option<usize>,
+}
+
+//
+// Implementations
+//
+
+impl Topology {
+    /// Reads the topology from `/sys`, or assumes one core per logical CPU
+    /// where that isn't possible.
+    pub fn detect() -> Topology {
+        Topology::from_sysfs(Path::new("/sys/devices/system/cpu")).unwrap_or_else(|| {
+            let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+            Topology {
+                cores: (0..cpus).map(|cpu| vec![cpu]).collect(),
+                l3: None,
+            }
+        })
+    }
+
+    /// Reads the topology from a sysfs CPU directory.
+    fn from_sysfs(root: &Path) -> Option<Topology> {
+        let online = parse_cpu_list(fs::read_to_string(root.join("online")).ok()?.trim())?;
+        let mut cores = BTreeMap::new();
+        let mut l3_caches = BTreeMap::new();
+        for &cpu in &online {
+            let dir = root.join(format!("cpu{}", cpu));
+            let read = |file: &str| fs::read_to_string(dir.join(file)).ok();
+            let id = |file: &str| read(file).and_then(|id| id.trim().parse::<usize>().ok());
+            let package = id("topology/physical_package_id").unwrap_or(0);
+            let core = id("topology/core_id").unwrap_or(cpu);
+            cores
+                .entry((package, core))
+                .or_insert_with(Vec::new)
+                .push(cpu);
+            for index in 0.. {
+                let cache = format!("cache/index{}/", index);
+                let level = match read(&(cache.clone() + "level")) {
+                    Some(level) => level,
+                    None => break,
+                };
+                if level.trim() != "3" {
+                    continue;
+                }
+                // CPUs sharing a cache list it identically, count it once
+                if let (Some(shared), Some(size)) = (
+                    read(&(cache.clone() + "shared_cpu_list")),
+                    read(&(cache + "size")).and_then(|size| parse_size(size.trim())),
+                ) {
+                    l3_caches.insert(shared.trim().to_string(), size);
+                }
+            }
+        }
+        Some(Topology {
+            cores: cores.into_values().collect(),
+            l3: if l3_caches.is_empty() {
+                None
+            } else {
+                Some(l3_caches.values().sum())
+            },
+        })
+    }
+
+    /// Physical cores.
+    pub fn cores(&self) -> usize {
+        self.cores.len()
+    }
+
+    /// How many threads to run, one per core while their scratchpads of
+    /// `memory` bytes each fit in L3.
+    pub fn threads_for(&self, memory: usize) -> usize {
+        let fit = self.l3.map_or(usize::MAX, |l3| l3 / memory.max(1));
+        fit.min(self.cores()).max(1)
+    }
+
+    /// Picks a CPU for each thread, spreading them over physical cores
+    /// before doubling up on siblings.
+    ///
+    /// `threads` overrides the thread count, and `mask` restricts the CPUs to
+    /// those whose bit is set. Threads are only pinned if the topology was
+    /// read or a mask was given.
+    pub fn schedule(
+        &self,
+        memory: usize,
+        threads: Option<usize>,
+        mask: Option<u64>,
+    ) -> Vec<Option<usize>> {
+        let depth = self.cores.iter().map(Vec::len).max().unwrap_or(0);
+        let mut cpus: Vec<usize> = (0..depth)
+            .flat_map(|sibling| self.cores.iter().filter_map(move |core| core.get(sibling)))
+            .cloned()
+            .filter(|&cpu| mask.map_or(true, |mask| cpu < 64 && mask & (1 << cpu) != 0))
+            .collect();
+        if let Some(mask) = mask {
+            // Masked CPUs missing from the topology are still the user's choice
+            let known: BTreeSet<usize> = cpus.iter().cloned().collect();
+            cpus.extend((0..64).filter(|cpu| mask & (1 << cpu) != 0 && !known.contains(cpu)));
+        }
+        let threads = threads.unwrap_or_else(|| {
+            let threads = self.threads_for(memory);
+            if mask.is_some() {
+                threads.min(cpus.len())
+            } else {
+                threads
+            }
+        });
+        let pin = mask.is_some() || self.l3.is_some();
+        (0..threads)
+            .map(|thread| {
+                if pin && !cpus.is_empty() {
+                    Some(cpus[thread % cpus.len()])
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+//
+// Trait implementations
+//
+
+impl fmt::Display for Topology {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.l3 {
+            Some(l3) => write!(f, "{} cores, {} KB of L3 cache", self.cores(), l3 >> 10),
+            None => write!(f, "{} cores, unknown L3 cache", self.cores()),
+        }
+    }
+}
+
+//
+// Functions
+//
+
+/// Pins the calling thread to `cpu`, returning whether that succeeded.
+#[cfg(target_os = "linux")]
+pub fn pin(cpu: usize) -> bool {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) == 0
+    }
+}
+
+/// Pins the calling thread to `cpu`, returning whether that succeeded.
+#[cfg(not(target_os = "linux"))]
+pub fn pin(_cpu: usize) -> bool {
+    false
+}
+
+//
+// Private Functions
+//
+
+/// Parses a CPU list such as `0-3,8`.
+fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for range in list.split(',').filter(|range| !range.is_empty()) {
+        let mut bounds = range.splitn(2, '-');
+        let first: usize = bounds.next()?.parse().ok()?;
+        let last = match bounds.next() {
+            Some(last) => last.parse().ok()?,
+            None => first,
+        };
+        cpus.extend(first..=last);
+    }
+    Some(cpus)
+}
+
+/// Parses a cache size such as `32768K`.
+fn parse_size(size: &str) -> Option<usize> {
+    let (digits, unit) = match size.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => size.split_at(index),
+        None => (size, ""),
+    };
+    let scale = match unit {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return None,
+    };
+    digits.parse::<usize>().ok().map(|digits| digits * scale)
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Writes a sysfs tree of 2 cores with 2 threads each, sharing 4 MB of L3.
+    fn fake_sysfs() -> PathBuf {
+        let root = std::env::temp_dir().join(format!("cnminer-sysfs-{}", std::process::id()));
+        let write = |file: String, contents: &str| {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        };
+        write("online".to_string(), "0-3\n");
+        for cpu in 0..4 {
+            let dir = format!("cpu{}/", cpu);
+            write(dir.clone() + "topology/physical_package_id", "0\n");
+            write(dir.clone() + "topology/core_id", &format!("{}\n", cpu % 2));
+            write(dir.clone() + "cache/index0/level", "2\n");
+            write(dir.clone() + "cache/index0/size", "1024K\n");
+            write(dir.clone() + "cache/index0/shared_cpu_list", "0\n");
+            write(dir.clone() + "cache/index1/level", "3\n");
+            write(dir.clone() + "cache/index1/size", "4096K\n");
+            write(dir + "cache/index1/shared_cpu_list", "0-3\n");
+        }
+        root
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn schedule() {
+        let root = fake_sysfs();
+        let topology = Topology::from_sysfs(&root).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(topology.cores, vec![vec![0, 2], vec![1, 3]]);
+        assert_eq!(topology.l3, Some(4 << 20));
+        assert_eq!(topology.threads_for(2 << 20), 2);
+        assert_eq!(topology.threads_for(4 << 20), 1);
+        assert_eq!(topology.threads_for(1 << 20), 2);
+        assert_eq!(
+            topology.schedule(2 << 20, None, None),
+            vec![Some(0), Some(1)]
+        );
+        assert_eq!(
+            topology.schedule(2 << 20, Some(3), None),
+            vec![Some(0), Some(1), Some(2)]
+        );
+        assert_eq!(
+            topology.schedule(1 << 20, None, Some(0b1100)),
+            vec![Some(2), Some(3)]
+        );
+        assert_eq!(topology.schedule(2 << 20, None, Some(0b10)), vec![Some(1)]);
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_cpu_list("0-3,8"), Some(vec![0, 1, 2, 3, 8]));
+        assert_eq!(parse_cpu_list("x"), None);
+        assert_eq!(parse_size("32768K"), Some(32 << 20));
+        assert_eq!(parse_size("8M"), Some(8 << 20));
+        assert_eq!(parse_size("8Q"), None);
+    }
+}
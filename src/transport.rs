@@ -0,0 +1,268 @@
+//
+// Imports
+//
+
+use super::protocol::ValidatedPool;
+use native_tls::{Certificate, HandshakeError, TlsConnector};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+//
+// Constants
+//
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+//
+// Enumerations
+//
+
+/// How the connection to a pool is carried.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Transport {
+    /// Plaintext TCP, `stratum+tcp://`.
+    #[default]
+    Tcp,
+    /// TLS over TCP, `stratum+ssl://`.
+    Tls,
+}
+
+//
+// Traits
+//
+
+/// An established, bidirectional pool connection.
+pub trait Connection: Read + Write {}
+
+//
+// Implementations
+//
+
+impl Transport {
+    /// Looks up the transport for a URL scheme.
+    pub fn from_scheme(scheme: &str) -> Option<Transport> {
+        match scheme {
+            "stratum+tcp" => Some(Transport::Tcp),
+            "stratum+ssl" | "stratum+tls" => Some(Transport::Tls),
+            _ => None,
+        }
+    }
+
+    /// The URL scheme of this transport.
+    pub fn scheme(self) -> &'static str {
+        match self {
+            Transport::Tcp => "stratum+tcp",
+            Transport::Tls => "stratum+ssl",
+        }
+    }
+}
+
+//
+// Trait implementations
+//
+
+impl<T: Read + Write> Connection for T {}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.scheme())
+    }
+}
+
+//
+// Functions
+//
+
+/// Connects to a pool over its configured transport.
+///
+/// Reads on the returned connection time out after `read_timeout`.
+pub fn connect(
+    pool: &ValidatedPool,
+    read_timeout: Duration,
+    write_timeout: Duration,
+) -> io::Result<Box<dyn Connection>> {
+    let stream = TcpStream::connect(&pool.endpoint)?;
+    stream.set_write_timeout(Some(write_timeout))?;
+    match pool.transport {
+        Transport::Tcp => {
+            stream.set_read_timeout(Some(read_timeout))?;
+            Ok(Box::new(stream))
+        }
+        Transport::Tls => {
+            stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+            let stream = handshake(pool, stream, &[])?;
+            stream.get_ref().set_read_timeout(Some(read_timeout))?;
+            Ok(Box::new(stream))
+        }
+    }
+}
+
+/// Returns the SHA-256 fingerprint of a DER-encoded certificate.
+pub fn fingerprint(der: &[u8]) -> Vec<u8> {
+    Sha256::digest(der).to_vec()
+}
+
+//
+// Private Functions
+//
+
+/// Negotiates TLS, verifying the pinned fingerprint if there is one.
+///
+/// A pinned certificate replaces the CA and hostname checks, as pools
+/// commonly use self-signed certificates. Without a pin the certificate
+/// must chain to the system store or one of `roots`.
+fn handshake(
+    pool: &ValidatedPool,
+    stream: TcpStream,
+    roots: &[Certificate],
+) -> io::Result<native_tls::TlsStream<TcpStream>> {
+    let mut builder = TlsConnector::builder();
+    for root in roots {
+        builder.add_root_certificate(root.clone());
+    }
+    let connector = builder
+        .danger_accept_invalid_certs(pool.fingerprint.is_some())
+        .danger_accept_invalid_hostnames(pool.fingerprint.is_some())
+        .build()
+        .map_err(io::Error::other)?;
+    let stream = connector
+        .connect(&pool.host, stream)
+        .map_err(|err| match err {
+            HandshakeError::Failure(err) => io::Error::new(ErrorKind::InvalidData, err),
+            HandshakeError::WouldBlock(_) => {
+                io::Error::new(ErrorKind::TimedOut, "TLS handshake timed out")
+            }
+        })?;
+    if let Some(ref expected) = pool.fingerprint {
+        let certificate = stream
+            .peer_certificate()
+            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "no peer certificate"))?;
+        let der = certificate
+            .to_der()
+            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+        if fingerprint(&der) != *expected {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "certificate fingerprint mismatch",
+            ));
+        }
+    }
+    Ok(stream)
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use native_tls::{Identity, TlsAcceptor};
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Serves one TLS connection with a fresh self-signed certificate.
+    ///
+    /// Returns the pool to connect to and the certificate fingerprint.
+    fn stand_in_server() -> (ValidatedPool, Vec<u8>) {
+        let key = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let pool = serve(&key.cert, &key.key_pair);
+        (pool, fingerprint(key.cert.der()))
+    }
+
+    /// Serves one TLS connection with `cert`.
+    fn serve(cert: &rcgen::Certificate, key_pair: &rcgen::KeyPair) -> ValidatedPool {
+        let identity =
+            Identity::from_pkcs8(cert.pem().as_bytes(), key_pair.serialize_pem().as_bytes())
+                .unwrap();
+        let acceptor = TlsAcceptor::new(identity).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let pool = ValidatedPool {
+            host: "localhost".to_string(),
+            endpoint: listener.local_addr().unwrap().to_string(),
+            transport: Transport::Tls,
+            fingerprint: None,
+            user: String::new(),
+            pass: String::new(),
+        };
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            if let Ok(mut stream) = acceptor.accept(stream) {
+                stream.write_all(b"hello\n").ok();
+            }
+        });
+        pool
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn pinned() {
+        let (mut pool, fingerprint) = stand_in_server();
+        pool.fingerprint = Some(fingerprint);
+        let timeout = Duration::from_secs(5);
+        let stream = connect(&pool, timeout, timeout).unwrap();
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        assert_eq!(line, "hello\n");
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn fingerprint_mismatch() {
+        let (mut pool, _) = stand_in_server();
+        pool.fingerprint = Some(vec![0; 32]);
+        let timeout = Duration::from_secs(5);
+        let err = connect(&pool, timeout, timeout).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn pin_overrides_trust() {
+        use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+
+        // A certificate for localhost issued by a trusted CA
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params
+            .distinguished_name
+            .push(DnType::CommonName, "cnminer test CA");
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "localhost");
+        let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+        let roots = [Certificate::from_der(ca.der()).unwrap()];
+        let connect = |pool: &ValidatedPool| {
+            let stream = TcpStream::connect(&pool.endpoint).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            handshake(pool, stream, &roots)
+        };
+
+        // Valid as is, yet a wrong pin still refuses it
+        connect(&serve(&cert, &key)).unwrap();
+        let mut pool = serve(&cert, &key);
+        pool.fingerprint = Some(vec![0; 32]);
+        let err = connect(&pool).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "certificate fingerprint mismatch");
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn untrusted() {
+        let (pool, _) = stand_in_server();
+        let timeout = Duration::from_secs(5);
+        assert!(connect(&pool, timeout, timeout).is_err());
+    }
+}
@@ -0,0 +1,349 @@
+//
+// Imports
+//
+
+use super::algorithm::{Algorithm, Hasher, MAX_WAYS};
+use super::hex;
+use super::protocol::{StratumJob, Target};
+use super::topology;
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+//
+// Constants
+//
+
+const NONCE_OFFSET: usize = 39;
+const NONCE_SIZE: usize = 4;
+const HASH_SIZE: usize = 32;
+const NONCES: u64 = 1 << 32;
+
+//
+// Thread locals
+//
+
+thread_local! {
+    /// Scratchpad of the current worker thread, allocated on first use.
+    static HASHER: RefCell<Hasher> = RefCell::new(Hasher::new());
+}
+
+//
+// Structures
+//
+
+/// A nonce whose hash meets the job target.
+pub struct Share {
+    pub job_id: String,
+    pub nonce: u32,
+    pub result: [u8; HASH_SIZE],
+}
+
+/// A job decoded into the form the workers hash.
+struct Work {
+    algorithm: &'static dyn Algorithm,
+    job_id: String,
+    blob: Vec<u8>,
+    target: Target,
+    height: u64,
+    generation: usize,
+}
+
+/// Work shared between the dispatcher thread and the `WorkerPool` handle.
+struct WorkState {
+    work: Mutex<Option<Arc<Work>>>,
+    available: Condvar,
+    generation: AtomicUsize,
+}
+
+/// Nonce-search worker pool.
+pub struct WorkerPool {
+    state: Arc<WorkState>,
+}
+
+//
+// Implementations
+//
+
+impl Work {
+    fn from_job(
+        job: &StratumJob,
+        algorithm: &'static dyn Algorithm,
+        generation: usize,
+    ) -> Option<Work> {
+        let blob = hex::decode(&job.blob)?;
+        if blob.len() < NONCE_OFFSET + NONCE_SIZE || blob.len() < algorithm.min_input_len() {
+            return None;
+        }
+        if algorithm.needs_height() && job.height.is_none() {
+            return None;
+        }
+        Some(Work {
+            algorithm,
+            job_id: job.job_id.clone(),
+            blob,
+            target: job.target,
+            height: job.height.unwrap_or(0),
+            generation,
+        })
+    }
+}
+
+impl WorkerPool {
+    /// Constructs a new `WorkerPool` reporting shares to `shares`, with a
+    /// thread for each entry of `cpus`, pinned to that CPU if given, and
+    /// each interleaving `ways` hashes of `algorithm`.
+    pub fn new(
+        shares: Sender<Share>,
+        algorithm: &'static dyn Algorithm,
+        ways: usize,
+        cpus: Vec<Option<usize>>,
+    ) -> WorkerPool {
+        let state = Arc::new(WorkState {
+            work: Mutex::new(None),
+            available: Condvar::new(),
+            generation: AtomicUsize::new(0),
+        });
+        let pool = ThreadPoolBuilder::new()
+            .thread_name(|i| format!("cnminer-worker-{}", i))
+            .num_threads(cpus.len())
+            .start_handler(move |i| {
+                let placement = match cpus[i] {
+                    Some(cpu) if topology::pin(cpu) => format!(" on CPU {}", cpu),
+                    Some(cpu) => format!(" unable to pin to CPU {}", cpu),
+                    None => String::new(),
+                };
+                // Allocate up front, so the page mode is known before mining
+                HASHER.with(|hasher| {
+                    let mut hasher = hasher.borrow_mut();
+                    algorithm.prepare(&mut hasher);
+                    hasher.set_ways(ways);
+                    println!(
+                        "Thread {}{}, {} scratchpad on {}",
+                        i,
+                        placement,
+                        algorithm.name(),
+                        hasher.pages()
+                    );
+                })
+            })
+            .build()
+            .expect("Unable to create worker threads");
+        let dispatch_state = state.clone();
+        thread::spawn(move || dispatch(pool, dispatch_state, shares, ways));
+        WorkerPool { state }
+    }
+
+    /// Replaces the current job, abandoning any work in progress.
+    ///
+    /// Returns `false` if the job could not be decoded or lacks what
+    /// `algorithm` needs to hash it.
+    pub fn set_job(&self, job: &StratumJob, algorithm: &'static dyn Algorithm) -> bool {
+        let generation = self.state.generation.load(Ordering::SeqCst) + 1;
+        let work = match Work::from_job(job, algorithm, generation) {
+            Some(work) => work,
+            None => return false,
+        };
+        let mut current = self.state.work.lock().unwrap();
+        self.state.generation.store(generation, Ordering::SeqCst);
+        *current = Some(Arc::new(work));
+        self.state.available.notify_all();
+        true
+    }
+
+    /// Abandons the current job without replacing it.
+    pub fn clear_job(&self) {
+        let mut current = self.state.work.lock().unwrap();
+        self.state.generation.fetch_add(1, Ordering::SeqCst);
+        *current = None;
+    }
+}
+
+//
+// Private Functions
+//
+
+/// Feeds the current job to the thread pool until it is replaced.
+fn dispatch(pool: ThreadPool, state: Arc<WorkState>, shares: Sender<Share>, ways: usize) {
+    let mut last_generation = 0;
+    loop {
+        let work = {
+            let mut current = state.work.lock().unwrap();
+            loop {
+                match *current {
+                    Some(ref work) if work.generation != last_generation => break work.clone(),
+                    _ => current = state.available.wait(current).unwrap(),
+                }
+            }
+        };
+        last_generation = work.generation;
+        pool.install(|| {
+            (0..batches(ways)).into_par_iter().try_for_each_init(
+                || vec![work.blob.clone(); ways],
+                |blobs, index| {
+                    let (first_nonce, count) = batch(index, ways);
+                    search(&state, &work, &mut blobs[..count], first_nonce, &shares)
+                },
+            )
+        })
+        .ok();
+    }
+}
+
+/// Number of batches of `ways` consecutive nonces covering every nonce.
+fn batches(ways: usize) -> u64 {
+    NONCES.div_ceil(ways as u64)
+}
+
+/// First nonce and nonce count of batch `index`, the last batch stopping
+/// short at `u32::MAX` rather than wrapping around.
+fn batch(index: u64, ways: usize) -> (u32, usize) {
+    let first = index * ways as u64;
+    (first as u32, (ways as u64).min(NONCES - first) as usize)
+}
+
+/// Hashes one nonce per blob from `first_nonce` on, reporting those that
+/// meet the target.
+fn search(
+    state: &WorkState,
+    work: &Work,
+    blobs: &mut [Vec<u8>],
+    first_nonce: u32,
+    shares: &Sender<Share>,
+) -> Result<(), ()> {
+    if state.generation.load(Ordering::Relaxed) != work.generation {
+        return Err(());
+    }
+    let ways = blobs.len();
+    let mut inputs: [&[u8]; MAX_WAYS] = [&[]; MAX_WAYS];
+    for (i, (blob, input)) in blobs.iter_mut().zip(inputs.iter_mut()).enumerate() {
+        let nonce = first_nonce + i as u32;
+        blob[NONCE_OFFSET..NONCE_OFFSET + NONCE_SIZE].copy_from_slice(&nonce.to_le_bytes());
+        *input = blob;
+    }
+    let mut results = [[0u8; HASH_SIZE]; MAX_WAYS];
+    HASHER.with(|hasher| {
+        let mut hasher = hasher.borrow_mut();
+        if hasher.ways() != ways {
+            hasher.set_ways(ways);
+        }
+        // Jobs of another size reallocate the scratchpads
+        if hasher.memory() != work.algorithm.memory() {
+            work.algorithm.prepare(&mut hasher);
+            println!(
+                "Thread {}, {} scratchpad on {}",
+                rayon::current_thread_index().unwrap_or(0),
+                work.algorithm.name(),
+                hasher.pages()
+            );
+        }
+        work.algorithm.hash_ways(
+            &mut hasher,
+            &inputs[..ways],
+            work.height,
+            &mut results[..ways],
+        )
+    });
+    for (i, result) in results[..ways].iter().enumerate() {
+        if work.target.meets(result) {
+            let share = Share {
+                job_id: work.job_id.clone(),
+                nonce: first_nonce + i as u32,
+                result: *result,
+            };
+            shares.send(share).map_err(|_| ())?;
+        }
+    }
+    Ok(())
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Difficulty;
+    use crate::registry;
+    use std::sync::mpsc::{self, Receiver};
+    use std::time::Duration;
+
+    fn job(job_id: &str) -> StratumJob {
+        StratumJob {
+            blob: "07".repeat(76),
+            job_id: job_id.to_string(),
+            target: Difficulty::new(1).target(),
+            height: None,
+            algorithm: None,
+        }
+    }
+
+    fn next_share(shares: &Receiver<Share>) -> Share {
+        shares
+            .recv_timeout(Duration::from_secs(60))
+            .expect("No share found")
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn shares() {
+        let algorithm = registry::by_name("cn-lite/1").unwrap();
+        let (share_tx, shares) = mpsc::channel();
+        let workers = WorkerPool::new(share_tx, algorithm, 1, vec![None]);
+        assert!(workers.set_job(&job("a"), algorithm));
+        let share = next_share(&shares);
+        assert_eq!(share.job_id, "a");
+        let mut blob = hex::decode(&job("a").blob).unwrap();
+        blob[NONCE_OFFSET..NONCE_OFFSET + NONCE_SIZE].copy_from_slice(&share.nonce.to_le_bytes());
+        let mut expected = [0u8; HASH_SIZE];
+        algorithm.hash(&mut Hasher::new(), &blob, 0, &mut expected);
+        assert_eq!(share.result, expected);
+
+        // A single thread finishes at most the hash in progress before
+        // moving on to the new job
+        assert!(workers.set_job(&job("b"), algorithm));
+        while next_share(&shares).job_id != "b" {}
+        workers.clear_job();
+        let mut abandoned = 0;
+        while let Ok(share) = shares.recv_timeout(Duration::from_secs(5)) {
+            assert_eq!(share.job_id, "b");
+            abandoned += 1;
+        }
+        assert!(abandoned <= 1);
+    }
+
+    #[test]
+    fn nonce_range() {
+        for ways in 1..=MAX_WAYS {
+            let last = batches(ways) - 1;
+            assert_eq!(batch(0, ways), (0, ways));
+            assert_eq!(batch(1, ways), (ways as u32, ways));
+            let (first_nonce, count) = batch(last, ways);
+            assert_eq!(
+                u64::from(first_nonce) + count as u64,
+                NONCES,
+                "{} ways",
+                ways
+            );
+            assert!(count >= 1 && count <= ways);
+        }
+        assert_eq!(batch(batches(3) - 1, 3), (u32::MAX, 1));
+    }
+
+    #[test]
+    fn invalid_job() {
+        let cn = registry::by_name("cn/0").unwrap();
+        assert!(Work::from_job(&job("a"), cn, 1).is_some());
+        // CryptoNight-R needs the job height
+        let cn_r = registry::by_name("cn/r").unwrap();
+        assert!(Work::from_job(&job("a"), cn_r, 1).is_none());
+        let mut short = job("a");
+        short.blob = "07".repeat(NONCE_OFFSET);
+        assert!(Work::from_job(&short, cn, 1).is_none());
+    }
+}